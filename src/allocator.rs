@@ -5,7 +5,8 @@ use bump::BumpAllocator;
 use linked_list::LinkedListAllocator;
 use fixed_size_block::FixedSizeBlockAllocator;
 
-#[global_allocator]
+// ホストでのテストではstdのアロケータを使う
+#[cfg_attr(not(test), global_allocator)]
 // static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
 // static ALLOCATOR: Locked<LinkedListAllocator> =
 //     Locked::new(LinkedListAllocator::new());
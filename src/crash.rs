@@ -0,0 +1,176 @@
+use core::arch::asm;
+use core::mem::{size_of, MaybeUninit};
+use cortex_m_semihosting::hprintln;
+use crate::fault::FaultStatus;
+use crate::process::ContextFrame;
+use crate::scheduler;
+use crate::systick;
+
+const CRASH_MAGIC: u32 = 0xDEAD_C0DE;
+const NAME_LEN: usize = 16;
+const STACK_WORDS: usize = 16;
+// アプリのスタックは例外フレームの分だけ残す
+const PROCESS_STACK_WORDS: usize = 8;
+
+// 致命的なフォールトの記録
+// .noinitに置くので、ウォームリセット後も残っている
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CrashRecord {
+    magic: u32,
+    // 例外で積まれたr0-r3, r12, lr, pc, xpsr
+    frame: [u32; 8],
+    exc_return: u32,
+    ipsr: u32,
+    status: FaultStatus,
+    // フォールトしたときに実行中(またはシステムコールを処理中)だったアプリ。いなければ0
+    pid: u32,
+    name: [u8; NAME_LEN],
+    ticks: u64,
+    // フォールトしたときのスタックの先頭
+    sp: u32,
+    stack: [u32; STACK_WORDS],
+    // 実行中だったアプリのPSPと、そこから積まれていた例外フレーム
+    // PSPがアプリのスタックの範囲外なら、process_stack_savedは0
+    psp: u32,
+    process_stack_saved: u32,
+    process_stack: [u32; PROCESS_STACK_WORDS],
+    checksum: u32,
+}
+
+#[link_section = ".noinit"]
+static mut CRASH_RECORD: MaybeUninit<CrashRecord> = MaybeUninit::uninit();
+
+impl CrashRecord {
+    pub fn report(&self) {
+        hprintln!("[Kernel]: previous boot crashed at tick {}", self.ticks);
+        if self.pid != 0 {
+            let len = self.name.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+            let name = core::str::from_utf8(&self.name[..len]).unwrap_or("?");
+            hprintln!("[Kernel]: while running process {} ({})", self.pid, name);
+        } else {
+            hprintln!("[Kernel]: no process was running (kernel context)");
+        }
+        let [r0, r1, r2, r3, r12, lr, pc, xpsr] = self.frame;
+        hprintln!(
+            "[Kernel]: pc {:#010x} lr {:#010x} xpsr {:#010x} EXC_RETURN {:#010x} IPSR {}",
+            pc,
+            lr,
+            xpsr,
+            self.exc_return,
+            self.ipsr,
+        );
+        hprintln!(
+            "[Kernel]: r0 {:#010x} r1 {:#010x} r2 {:#010x} r3 {:#010x} r12 {:#010x}",
+            r0,
+            r1,
+            r2,
+            r3,
+            r12,
+        );
+        self.status.report();
+        hprintln!("[Kernel]: stack at {:#010x}", self.sp);
+        for (i, words) in self.stack.chunks(4).enumerate() {
+            hprintln!(
+                "[Kernel]:   {:#010x}: {:08x} {:08x} {:08x} {:08x}",
+                self.sp as usize + i * 16,
+                words[0],
+                words[1],
+                words[2],
+                words[3],
+            );
+        }
+        if self.pid == 0 {
+            return;
+        }
+        if self.process_stack_saved == 0 {
+            hprintln!("[Kernel]: process sp {:#010x} is outside its stack", self.psp);
+            return;
+        }
+        hprintln!("[Kernel]: process stack at {:#010x}", self.psp);
+        for (i, words) in self.process_stack.chunks(4).enumerate() {
+            hprintln!(
+                "[Kernel]:   {:#010x}: {:08x} {:08x} {:08x} {:08x}",
+                self.psp as usize + i * 16,
+                words[0],
+                words[1],
+                words[2],
+                words[3],
+            );
+        }
+    }
+
+    // checksum以外の全ワードの和
+    fn compute_checksum(&self) -> u32 {
+        let words = (size_of::<CrashRecord>() - size_of::<u32>()) / size_of::<u32>();
+        let ptr = self as *const CrashRecord as *const u32;
+        (0..words).fold(0u32, |sum, i| sum.wrapping_add(unsafe { ptr.add(i).read() }))
+    }
+}
+
+// フォールトしたときの状態を記録する
+// frameは例外で積まれたフレームで、その後ろのスタックもいくらか残しておく
+pub fn save(frame: &ContextFrame, exc_return: u32, ipsr: u32, status: FaultStatus) {
+    let sp = frame as *const ContextFrame as *const u32;
+    let mut record = CrashRecord {
+        magic: CRASH_MAGIC,
+        frame: unsafe { sp.cast::<[u32; 8]>().read() },
+        exc_return,
+        ipsr,
+        status,
+        pid: 0,
+        name: [0; NAME_LEN],
+        ticks: systick::ticks(),
+        sp: sp as u32,
+        stack: [0; STACK_WORDS],
+        psp: 0,
+        process_stack_saved: 0,
+        process_stack: [0; PROCESS_STACK_WORDS],
+        checksum: 0,
+    };
+    for (i, word) in record.stack.iter_mut().enumerate() {
+        *word = unsafe { sp.add(i).read_volatile() };
+    }
+    if let Some((pid, name)) = scheduler::current_process() {
+        record.pid = pid;
+        let len = name.len().min(NAME_LEN);
+        record.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+    }
+    // 壊れたPSPを読んで再びフォールトしないよう、アプリのスタックの範囲内にあるときだけ読む
+    if let Some((base, end)) = scheduler::current_stack() {
+        let psp = psp();
+        record.psp = psp as u32;
+        if psp >= base && psp.checked_add(PROCESS_STACK_WORDS * 4).is_some_and(|e| e <= end) {
+            let words = psp as *const u32;
+            for (i, word) in record.process_stack.iter_mut().enumerate() {
+                *word = unsafe { words.add(i).read_volatile() };
+            }
+            record.process_stack_saved = 1;
+        }
+    }
+    record.checksum = record.compute_checksum();
+    unsafe { (&raw mut CRASH_RECORD).write(MaybeUninit::new(record)) };
+}
+
+// カーネルはMSPで動くので、PSPには最後にアプリから戻ってきたときの値が残っている
+fn psp() -> usize {
+    let psp: usize;
+    #[cfg(not(test))]
+    unsafe {
+        asm!("mrs {}, psp", out(reg) psp);
+    }
+    // ホストでのテストではアプリを実行しない
+    #[cfg(test)]
+    {
+        psp = 0;
+    }
+    psp
+}
+
+// 前回の起動で残されたクラッシュレコードを取り出して消す
+// 電源投入直後のRAMは不定なので、マジックナンバーとチェックサムが合うものだけを使う
+pub fn take() -> Option<CrashRecord> {
+    let record = unsafe { (&raw mut CRASH_RECORD).read_volatile().assume_init() };
+    unsafe { ((&raw mut CRASH_RECORD) as *mut u32).write_volatile(0) };
+    (record.magic == CRASH_MAGIC && record.checksum == record.compute_checksum()).then_some(record)
+}
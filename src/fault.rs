@@ -0,0 +1,148 @@
+use core::arch::naked_asm;
+use core::ptr::{read_volatile, write_volatile};
+use cortex_m_semihosting::hprintln;
+use crate::crash;
+use crate::process::ContextFrame;
+use crate::scb;
+use crate::scheduler;
+
+const CFSR_ADDR: usize = 0xE000_ED28;
+const HFSR_ADDR: usize = 0xE000_ED2C;
+const MMFAR_ADDR: usize = 0xE000_ED34;
+const BFAR_ADDR: usize = 0xE000_ED38;
+
+const CFSR_MMARVALID: u32 = 1 << 7;
+const CFSR_BFARVALID: u32 = 1 << 15;
+
+// CFSRはMemManage(bit0-7)・BusFault(bit8-15)・UsageFault(bit16-31)のステータスをまとめたもの
+const CFSR_CAUSES: [(u32, &str); 17] = [
+    (1 << 0, "MemManage: instruction access violation"),
+    (1 << 1, "MemManage: data access violation"),
+    (1 << 3, "MemManage: fault on exception return unstacking"),
+    (1 << 4, "MemManage: fault on exception entry stacking"),
+    (1 << 5, "MemManage: fault during FP lazy state preservation"),
+    (1 << 8, "BusFault: instruction bus error"),
+    (1 << 9, "BusFault: precise data bus error"),
+    (1 << 10, "BusFault: imprecise data bus error"),
+    (1 << 11, "BusFault: fault on exception return unstacking"),
+    (1 << 12, "BusFault: fault on exception entry stacking"),
+    (1 << 13, "BusFault: fault during FP lazy state preservation"),
+    (1 << 16, "UsageFault: undefined instruction"),
+    (1 << 17, "UsageFault: invalid state (EPSR.T or IT)"),
+    (1 << 18, "UsageFault: invalid EXC_RETURN on exception return"),
+    (1 << 19, "UsageFault: coprocessor access (FPU disabled?)"),
+    (1 << 24, "UsageFault: unaligned access"),
+    (1 << 25, "UsageFault: divide by zero"),
+];
+
+const HFSR_CAUSES: [(u32, &str); 3] = [
+    (1 << 1, "HardFault: bus fault on vector table read"),
+    (1 << 30, "HardFault: escalated from a configurable fault"),
+    (1 << 31, "HardFault: debug event"),
+];
+
+// フォールトのステータスレジスタの値
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FaultStatus {
+    cfsr: u32,
+    hfsr: u32,
+    mmfar: u32,
+    bfar: u32,
+}
+
+impl FaultStatus {
+    // 読み出して、次のフォールトのためにクリアする(1を書くとクリアされる)
+    pub fn take() -> Self {
+        unsafe {
+            let status = FaultStatus {
+                cfsr: read_volatile(CFSR_ADDR as *const u32),
+                hfsr: read_volatile(HFSR_ADDR as *const u32),
+                mmfar: read_volatile(MMFAR_ADDR as *const u32),
+                bfar: read_volatile(BFAR_ADDR as *const u32),
+            };
+            write_volatile(CFSR_ADDR as *mut u32, status.cfsr);
+            write_volatile(HFSR_ADDR as *mut u32, status.hfsr);
+            status
+        }
+    }
+
+    pub fn report(&self) {
+        hprintln!("[Kernel]: CFSR {:#010x} HFSR {:#010x}", self.cfsr, self.hfsr);
+        for (_, cause) in CFSR_CAUSES.iter().filter(|(bit, _)| self.cfsr & bit != 0) {
+            hprintln!("[Kernel]:   {}", cause);
+        }
+        for (_, cause) in HFSR_CAUSES.iter().filter(|(bit, _)| self.hfsr & bit != 0) {
+            hprintln!("[Kernel]:   {}", cause);
+        }
+        if self.cfsr & CFSR_MMARVALID != 0 {
+            hprintln!("[Kernel]:   faulting address (MMFAR) {:#010x}", self.mmfar);
+        }
+        if self.cfsr & CFSR_BFARVALID != 0 {
+            hprintln!("[Kernel]:   faulting address (BFAR) {:#010x}", self.bfar);
+        }
+    }
+}
+
+// フォールトハンドラ
+// - アプリ(PSP)で起きた場合はSwitchReason::Faultとしてカーネルへ戻し、カーネルが再起動などを決める
+// - カーネル(MSP)で起きた場合は、積まれたフレームとEXC_RETURN、例外番号をkernel_faultに渡す
+macro_rules! fault_handler {
+    ($name:ident) => {
+        #[cfg(not(test))]
+        #[unsafe(naked)]
+        #[no_mangle]
+        pub unsafe extern "C" fn $name() {
+            naked_asm!(
+                "tst lr, #0x4",
+                "bne 1f",
+                "mrs r0, msp",
+                "mov r1, lr",
+                "mrs r2, ipsr",
+                "b {kernel_fault}",
+                "1:",
+                "mov r0, #3",
+                "b {return_to_kernel}",
+                kernel_fault = sym kernel_fault,
+                return_to_kernel = sym crate::return_to_kernel,
+            );
+        }
+    };
+}
+
+fault_handler!(HardFault);
+fault_handler!(MemManage);
+fault_handler!(BusFault);
+fault_handler!(UsageFault);
+
+fn exception_name(ipsr: u32) -> &'static str {
+    match ipsr & 0x1FF {
+        3 => "HardFault",
+        4 => "MemManage",
+        5 => "BusFault",
+        6 => "UsageFault",
+        _ => "unknown exception",
+    }
+}
+
+// カーネル自身のフォールトからは復帰できないので、クラッシュレコードを残してリセットする
+extern "C" fn kernel_fault(frame: &ContextFrame, exc_return: u32, ipsr: u32) -> ! {
+    hprintln!(
+        "[Kernel]: {} in kernel at pc {:#010x} (lr {:#010x}, xpsr {:#010x}, EXC_RETURN {:#010x})",
+        exception_name(ipsr),
+        frame.return_addr,
+        frame.lr,
+        frame.xpsr,
+        exc_return,
+    );
+    // システムコールの処理中なら、どのアプリのためにカーネルが動いていたか
+    match scheduler::current_process() {
+        Some((pid, name)) => hprintln!("[Kernel]: while running process {} ({})", pid, name),
+        None => hprintln!("[Kernel]: no process was running (kernel context)"),
+    }
+    let status = FaultStatus::take();
+    status.report();
+    crash::save(frame, exc_return, ipsr, status);
+    hprintln!("[Kernel]: resetting");
+    scb::system_reset()
+}
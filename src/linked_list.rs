@@ -68,10 +68,72 @@ impl <'a, T> LinkedList<'a, T> {
         }
     }
 
+    // predがtrueを返す最初の要素の前にitemを挿入する
+    // 該当する要素がなければ末尾に追加する
+    pub fn insert_before<F>(&mut self, item: &'a mut ListItem<'a, T>, pred: F)
+    where
+        F: Fn(&T) -> bool,
+    {
+        let mut prev: Option<NonNull<ListItem<'a, T>>> = None;
+        let mut current = self.head;
+        while let Some(ptr) = current {
+            let current_item = unsafe { ptr.as_ref() };
+            if pred(&current_item.value) {
+                break;
+            }
+            prev = current;
+            current = current_item.next;
+        }
+
+        item.next = current;
+        let ptr = unsafe { NonNull::new_unchecked(item as *mut ListItem<T>) };
+        if let Some(mut i) = prev {
+            unsafe { i.as_mut().next = Some(ptr) }
+        } else {
+            self.head = Some(ptr)
+        }
+        if current.is_none() {
+            self.last = Some(ptr);
+        }
+    }
+
+    // predがtrueを返す最初の要素をリストから外して返す
+    pub fn remove<F>(&mut self, pred: F) -> Option<&'a mut ListItem<'a, T>>
+    where
+        F: Fn(&T) -> bool,
+    {
+        let mut prev: Option<NonNull<ListItem<'a, T>>> = None;
+        let mut current = self.head;
+        while let Some(ptr) = current {
+            let current_item = unsafe { &mut *ptr.as_ptr() };
+            if pred(&current_item.value) {
+                let next = current_item.next.take();
+                if let Some(mut i) = prev {
+                    unsafe { i.as_mut().next = next }
+                } else {
+                    self.head = next
+                }
+                if next.is_none() {
+                    self.last = prev;
+                }
+                return Some(current_item);
+            }
+            prev = current;
+            current = current_item.next;
+        }
+        None
+    }
+
     pub fn is_empty(&self) -> bool {
         self.head.is_none()
     }
 
+    pub fn head(&self) -> Option<&T> {
+        self.head.map(|ptr| unsafe { &*ptr.as_ptr() }.deref())
+    }
+
+    // テストでだけ使う
+    #[cfg(test)]
     pub fn head_mut(&mut self) -> Option<&mut T> {
         self.head.map(|ptr| unsafe { &mut *ptr.as_ptr() }.deref_mut())
     }
@@ -87,16 +149,19 @@ impl <'a, T> LinkedList<'a, T> {
         }
 
         self.head = next;
-        
-        result.map(|ptr| unsafe { &mut *ptr.as_ptr() })
+
+        // 取り出した要素を別のリストにpushしたときに古いnextが残らないようにする
+        result.map(|mut ptr| unsafe {
+            ptr.as_mut().next = None;
+            &mut *ptr.as_ptr()
+        })
     }
 }
 
 
 #[cfg(test)]
 mod test {
-    use ListItem;
-    use LinkedList;
+    use super::{LinkedList, ListItem};
 
     #[test]
     fn test_list() {
@@ -133,4 +198,55 @@ mod test {
 
         assert!(list.is_empty());
     }
+
+    #[test]
+    fn test_insert_before() {
+        let mut item1 = ListItem::new(1);
+        let mut item3 = ListItem::new(3);
+        let mut item2 = ListItem::new(2);
+        let mut item4 = ListItem::new(4);
+        let mut item0 = ListItem::new(0);
+        let mut list = LinkedList::new();
+
+        list.insert_before(&mut item1, |&v| v > 1);
+        list.insert_before(&mut item3, |&v| v > 3);
+        list.insert_before(&mut item2, |&v| v > 2);
+        list.insert_before(&mut item4, |&v| v > 4);
+        list.insert_before(&mut item0, |&v| v > 0);
+
+        assert_eq!(Some(&0), list.head());
+        for i in 0..5 {
+            let result: &u32 = list.pop().unwrap();
+            assert_eq!(i, *result);
+        }
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut item1 = ListItem::new(1);
+        let mut item2 = ListItem::new(2);
+        let mut item3 = ListItem::new(3);
+        let mut item4 = ListItem::new(4);
+        let mut list = LinkedList::new();
+
+        list.push(&mut item1);
+        list.push(&mut item2);
+        list.push(&mut item3);
+
+        let result2: &u32 = list.remove(|&v| v == 2).unwrap();
+        assert_eq!(2, *result2);
+        assert!(list.remove(|&v| v == 2).is_none());
+        let result3: &u32 = list.remove(|&v| v == 3).unwrap();
+        assert_eq!(3, *result3);
+
+        // 末尾を外した後もpushできる
+        list.push(&mut item4);
+        let result1: &u32 = list.remove(|&v| v == 1).unwrap();
+        assert_eq!(1, *result1);
+        assert_eq!(Some(&4), list.head());
+        let result4: &u32 = list.pop().unwrap();
+        assert_eq!(4, *result4);
+        assert!(list.is_empty());
+    }
 }
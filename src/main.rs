@@ -1,17 +1,20 @@
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 #![no_std]
-#![feature(naked_functions)]
+// ホストでのテストでは、ターゲット専用のハンドラやアプリを除いてビルドする
+#![cfg_attr(test, allow(dead_code, unused_imports, unused_variables, unused_unsafe, unreachable_code))]
 
-use core::panic::PanicInfo;
 use core::ptr;
-use core::arch::asm;
 use core::arch::naked_asm;
 use cortex_m_semihosting::hprintln;
 
 mod systick;
+use systick::{ClockSource, InitError};
+mod scb;
 
 mod process;
-use process::Process;
+use process::{PeriodicTask, Process, RestartPolicy};
+
+mod process_table;
 
 mod linked_list;
 use linked_list::ListItem;
@@ -19,24 +22,50 @@ use linked_list::ListItem;
 mod scheduler;
 use scheduler::Scheduler;
 
+mod syscall;
+
 extern crate alloc;
 use alloc::{boxed::Box, vec::Vec};
 mod allocator;
 
+mod timer;
+
+mod mpu;
+use mpu::{Access, Region};
+
+mod fault;
+#[cfg(not(test))]
+use fault::{BusFault, HardFault, MemManage, UsageFault};
+
+mod crash;
+
+mod panic;
+use panic::PanicAction;
+
+// STM32F401はリセット後に内部発振器(HSI)の16MHzで動く
+const CORE_CLOCK_HZ: u32 = 16_000_000;
+const TICK_HZ: u32 = 10;
+
+// MPUのリージョンにするため、スタックはサイズと同じ境界に揃える
+#[repr(C, align(2048))]
+struct AppStack([u8; 2048]);
+
+// アプリ間の共有メモリ。アクセスを許可したアプリだけが読み書きできる
+#[repr(C, align(32))]
+struct Mailbox([u32; 8]);
+static mut MAILBOX: Mailbox = Mailbox([0; 8]);
+
 pub union Vector {
     reserved: u32,
     handler: unsafe extern "C" fn(),
 }
 
+#[cfg(not(test))]
 extern "C" {
     fn NMI();
-    fn HardFault();
-    fn MemManage();
-    fn BusFault();
-    fn UsageFault();
-    fn PendSV();
 }
 
+#[cfg(not(test))]
 #[link_section = ".vector_table.exceptions"]
 #[no_mangle]
 pub static EXCEPTIONS: [Vector; 14] = [
@@ -63,34 +92,85 @@ pub extern "C" fn DefaultExceptionHandler() {
 
 #[no_mangle]
 pub extern "C" fn SysTick() {
-    hprintln!("Systick interrupt");
+    systick::tick();
+    scb::set_pendsv();
 }
 
-#[naked]
+// カーネルとアプリの切り替え
+// - カーネル側のEXC_RETURNはカーネルのスタックに積まれたr12の位置に保存しておく
+// - アプリ側のEXC_RETURNはカーネル側のr3で受け渡す
+// - FPUの遅延スタッキングが保留中なら、別のフレームに戻る前にFPU命令を実行して退避させる
+/// # Safety
+/// 例外ハンドラとしてベクタテーブルからだけ呼ばれる。直接呼んではいけない
+#[cfg(not(test))]
+#[unsafe(naked)]
 #[no_mangle]
 pub unsafe extern "C" fn SVCall() {
     naked_asm!(
-        "cmp lr, #0xfffffff9",
+        "tst lr, #0x4",
         "bne 1f",
+        // カーネルからアプリへ
+        "str lr, [sp, #16]",
+        "tst lr, #0x10",
+        "it eq",
+        "vmoveq.f32 s0, s0",
         "mov r0, #1",
         "msr CONTROL, r0",
-        "movw lr, #0xfffd",
-        "movt lr, #0xffff",
-        "bx lr",
+        "isb",
+        "bx r3",
         "1:",
+        // カーネル側のr0にSwitchReason::Syscallを返す
+        "mov r0, #1",
+        "b {return_to_kernel}",
+        return_to_kernel = sym return_to_kernel,
+    );
+}
+
+// アプリ実行中にPendSVが発生したら、SVCallと同様にカーネルへ戻る
+// カーネル実行中の場合は何もしない
+/// # Safety
+/// 例外ハンドラとしてベクタテーブルからだけ呼ばれる。直接呼んではいけない
+#[cfg(not(test))]
+#[unsafe(naked)]
+#[no_mangle]
+pub unsafe extern "C" fn PendSV() {
+    naked_asm!(
+        "tst lr, #0x4",
+        "beq 1f",
+        // カーネル側のr0にSwitchReason::Preemptedを返す
+        "mov r0, #2",
+        "b {return_to_kernel}",
+        "1:",
+        "bx lr",
+        return_to_kernel = sym return_to_kernel,
+    );
+}
+
+// r0に戻る理由を入れて、アプリ実行中の例外ハンドラから分岐してくる
+#[cfg(not(test))]
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn return_to_kernel() {
+    naked_asm!(
+        "str r0, [sp]",
+        "str lr, [sp, #12]",
+        "tst lr, #0x10",
+        "it eq",
+        "vmoveq.f32 s0, s0",
         "mov r0, #0",
         "msr CONTROL, r0",
-        "movw lr, #0xfff9",
-        "movt lr, #0xffff",
+        "isb",
+        "ldr lr, [sp, #16]",
         "bx lr",
     );
 }
 
 // The reset vector, a pointer into the reset handler
+#[cfg(not(test))]
 #[link_section = ".vector_table.reset_vector"]
 #[no_mangle]
 pub static RESET_VECTOR: unsafe extern "C" fn() -> ! = Reset;
 
+#[cfg(not(test))]
 #[no_mangle]
 pub unsafe extern "C" fn Reset() -> ! {
     extern "C" {
@@ -101,6 +181,13 @@ pub unsafe extern "C" fn Reset() -> ! {
         static mut _edata: u8;
     }
 
+    // FPU命令が使われる前に有効にする
+    scb::enable_fpu();
+
+    // .noinitに残っている前回のクラッシュレコードを、.bssを初期化する前に取り出す
+    // hprintlnは.bssを使うので、表示するのは初期化した後
+    let crash = crash::take();
+
     let count = &raw const _ebss as *const u8 as usize - &raw const _sbss as *const u8 as usize;
     ptr::write_bytes(&raw mut _sbss as *mut u8, 0, count);
 
@@ -108,37 +195,71 @@ pub unsafe extern "C" fn Reset() -> ! {
     ptr::copy_nonoverlapping(&raw mut _sidata as *const u8, &raw mut _sdata as *mut u8, count);
 
     hprintln!("Reset");
+    if let Some(record) = crash {
+        record.report();
+    }
+
+    // パニックしたアプリだけを終了させる
+    panic::set_action(PanicAction::KillProcess);
 
-    systick::init();
+    scb::init();
+    scb::enable_fault_handlers();
+    mpu::init();
+    // プロセッサクロックでは1tickがRVRに収まらない場合は、外部参照クロック(HCLK/8)でカウントする
+    systick::init(CORE_CLOCK_HZ, ClockSource::Processor, TICK_HZ)
+        .or_else(|e| match e {
+            InitError::ReloadOutOfRange => systick::init(CORE_CLOCK_HZ / 8, ClockSource::External, TICK_HZ),
+            e => Err(e),
+        })
+        .unwrap();
 
     #[link_section = ".app_stack"]
-    static mut APP_STACK: [u8; 2048] = [0; 2048];
+    static mut APP_STACK: AppStack = AppStack([0; 2048]);
     static APP_STACK_LEN: usize = 2048;
     #[link_section = ".app_stack"]
-    static mut APP_STACK2: [u8; 2048] = [0; 2048];
+    static mut APP_STACK2: AppStack = AppStack([0; 2048]);
     static APP_STACK2_LEN: usize = 2048;
     #[link_section = ".app_stack"]
-    static mut APP_STACK3: [u8; 2048] = [0; 2048];
+    static mut APP_STACK3: AppStack = AppStack([0; 2048]);
     static APP_STACK3_LEN: usize = 2048;
+    #[link_section = ".app_stack"]
+    static mut APP_STACK4: AppStack = AppStack([0; 2048]);
+    static APP_STACK4_LEN: usize = 2048;
 
-    let process1 = Process::new(&raw mut APP_STACK as *mut u8, &APP_STACK_LEN, app_main);
+    // MAILBOXはapp1が書き込み、app2が読み出す
+    let mailbox = &raw mut MAILBOX as usize;
+    let mailbox_size = core::mem::size_of::<Mailbox>();
+
+    let mut process1 = Process::new("app1", &raw mut APP_STACK as *mut u8, &APP_STACK_LEN, app_main, 1, 0);
+    process1.grant(Region::new(mailbox, mailbox_size, Access::ReadWrite).unwrap()).unwrap();
     let mut item1 = ListItem::new(process1);
-    let process2 = Process::new(&raw mut APP_STACK2 as *mut u8, &APP_STACK2_LEN, app_main2);
+    let mut process2 = Process::new("app2", &raw mut APP_STACK2 as *mut u8, &APP_STACK2_LEN, periodic_main, 2, 1);
+    process2.set_periodic(PeriodicTask { period: 2, deadline: 2, wcet: 1 }, 0);
+    process2.grant(Region::new(mailbox, mailbox_size, Access::ReadOnly).unwrap()).unwrap();
     let mut item2 = ListItem::new(process2);
-    let process3 = Process::new(&raw mut APP_STACK3 as *mut u8, &APP_STACK3_LEN, app_main3);
+    let mut process3 = Process::new("app3", &raw mut APP_STACK3 as *mut u8, &APP_STACK3_LEN, busy_main, 3, 2);
+    process3.set_restart_policy(RestartPolicy::Always);
     let mut item3 = ListItem::new(process3);
+    // フォールトするたびに待ち時間を倍にしながら3回まで再起動する
+    let mut process4 = Process::new("app4", &raw mut APP_STACK4 as *mut u8, &APP_STACK4_LEN, faulty_main, 4, 1);
+    process4.set_restart_policy(RestartPolicy::UpTo { max_restarts: 3, backoff: 10 });
+    let mut item4 = ListItem::new(process4);
 
     let mut sched = Scheduler::new();
-    sched.push(&mut item1);
-    sched.push(&mut item2);
-    sched.push(&mut item3);
+    sched.push(&mut item1).unwrap();
+    sched.push(&mut item2).unwrap();
+    sched.push(&mut item3).unwrap();
+    sched.push(&mut item4).unwrap();
+    sched.set_sleep_hook(board_sleep_hook);
+    sched.print_processes();
 
     hprintln!("[Kernel]");
     hprintln!("App Start");
 
+    // spawnしたアプリのスタックもヒープから確保する
     #[link_section = ".heap"]
-    static mut HEAP: [u8; 4096] = [0; 4096];
-    static HEAP_SIZE: usize = 4096;
+    static mut HEAP: [u8; 16384] = [0; 16384];
+    static HEAP_SIZE: usize = 16384;
     allocator::init_heap(&raw mut HEAP as usize, HEAP_SIZE);
 
     let heap_value = Box::new(41);
@@ -158,42 +279,112 @@ pub unsafe extern "C" fn Reset() -> ! {
     }
     assert_eq!(*long_lived, 1);
 
+    sched.start_timer_service().unwrap();
     sched.exec();
 
     
 }
 
-extern "C" fn app_main() -> ! {
+// STM32F401のStopモードではSysTickも止まるので、RTCやEXTIで起床できるようにするまではディープスリープに入らない
+// 起床要因を設定したら、次の起床まで十分に時間があるときだけtrueを返す
+fn board_sleep_hook(_idle_ticks: Option<u64>) -> bool {
+    false
+}
+
+extern "C" fn app_main(id: usize) -> i32 {
     let mut i = 0;
+    let mut timer = None;
     loop {
-        hprintln!("APP1: {}", i);
-        unsafe { 
-            asm!("svc 0");
+        println!("APP{}: {}", id, i);
+        if i == 0 {
+            // 同じエントリポイントを引数を変えて複数起動し、終了を待つ
+            let mut workers = [0; 2];
+            for (worker, worker_id) in workers.iter_mut().zip(1..) {
+                match syscall::spawn(worker_main, worker_id, 1024, "worker", 1) {
+                    Ok(pid) => *worker = pid,
+                    Err(e) => println!("APP{}: spawn failed {:?}", id, e),
+                }
+            }
+            for pid in workers.into_iter().filter(|&pid| pid != 0) {
+                match syscall::try_wait(pid) {
+                    Err(syscall::Error::WouldBlock) => println!("APP{}: worker {} is still running", id, pid),
+                    result => println!("APP{}: worker {} finished {:?}", id, pid, result),
+                }
+                let start = syscall::now();
+                let result = syscall::wait(pid);
+                println!("APP{}: worker {} exited with {:?} after {} us", id, pid, result, syscall::now() - start);
+            }
+
+            println!("APP{}: stack peak {:?}", id, syscall::stack_peak(0));
+
+            // コールバックはタイマータスクの中で呼ばれる
+            timer = syscall::timer_create(timer_callback, id, systick::ms_to_ticks(1000), true).ok();
+        }
+        let result = match (i, timer) {
+            (0, Some(timer)) => syscall::timer_start(timer),
+            (1, Some(timer)) => syscall::timer_change_period(timer, systick::ms_to_ticks(2000)),
+            (2, Some(timer)) => syscall::timer_stop(timer),
+            // 止めたタイマーを現在から再開する
+            (3, Some(timer)) => syscall::timer_reset(timer),
+            // 使い終わったタイマーのIDを解放する
+            (6, Some(timer)) => syscall::timer_delete(timer),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            println!("APP{}: timer failed {:?}", id, e);
+        }
+        unsafe {
+            (&raw mut MAILBOX.0[0]).write_volatile(i);
         }
+        syscall::sleep_ms(10_000);
         i += 1;
     }
 }
 
-extern "C" fn app_main2() -> ! {
-    loop {
-        hprintln!("APP2");
-        unsafe { 
-            asm!("svc 0");
+extern "C" fn timer_callback(id: usize) {
+    println!("TIMER{}: {} us", id, syscall::now());
+}
+
+// 実行時にspawnされ、終了するとスタックが解放される
+extern "C" fn worker_main(id: usize) -> i32 {
+    for i in 0..3 {
+        println!("WORKER{}: {}", id, i);
+        // パニックしたアプリだけが終了し、待っているアプリにはEXIT_CODE_PANICが返る
+        if id == 2 && i == 1 {
+            panic!("worker {} gave up", id);
         }
+        syscall::yield_now();
     }
+    id as i32
 }
 
-extern "C" fn app_main3() -> ! {
-    loop {
-        hprintln!("APP3");
-        unsafe { 
-            asm!("svc 0");
+extern "C" fn periodic_main(id: usize) -> i32 {
+    for _ in 0..10 {
+        let mail = unsafe { (&raw const MAILBOX.0[0]).read_volatile() };
+        println!("APP{}: mail {}", id, mail);
+        if syscall::wait_next_period().is_err() {
+            syscall::exit(-1);
         }
     }
+    0
+}
+
+// yieldしなくても、SysTickによって他のアプリに切り替わる
+// 切り替わってもFPUのレジスタは保持される
+extern "C" fn busy_main(id: usize) -> i32 {
+    let mut x = 0.0f32;
+    loop {
+        println!("APP{}: {}", id, x);
+        x += 0.5;
+    }
 }
 
-#[panic_handler]
-fn panic(_panic: &PanicInfo<'_>) -> ! {
-    hprintln!("Panic!");
-    loop {}
-}
\ No newline at end of file
+// 許可されていないメモリに書き込んでフォールトする
+extern "C" fn faulty_main(id: usize) -> i32 {
+    println!("APP{}: about to fault", id);
+    // アクセスを許可されていないので、MemManageフォールトになる
+    unsafe {
+        (&raw mut MAILBOX.0[0]).write_volatile(id as u32);
+    }
+    0
+}
@@ -0,0 +1,239 @@
+use core::arch::asm;
+use core::ptr::write_volatile;
+
+const MPU_CTRL_ADDR: usize = 0xE000_ED94;
+const MPU_RBAR_ADDR: usize = 0xE000_ED9C;
+const MPU_RASR_ADDR: usize = 0xE000_EDA0;
+
+// 特権モードではリージョン外もデフォルトのメモリマップでアクセスできる
+const MPU_CTRL_ENABLE: u32 = 1 << 0;
+const MPU_CTRL_PRIVDEFENA: u32 = 1 << 2;
+
+// RBARに書くときにREGIONフィールドでリージョン番号も指定する
+const RBAR_VALID: u32 = 1 << 4;
+
+const RASR_ENABLE: u32 = 1 << 0;
+const RASR_XN: u32 = 1 << 28;
+// Normalメモリ、ライトスルー
+const RASR_NORMAL: u32 = 1 << 17;
+// AP: 特権/非特権のアクセス権
+const AP_PRIV_RW: u32 = 0b001 << 24;
+const AP_PRIV_RW_USER_RO: u32 = 0b010 << 24;
+const AP_FULL: u32 = 0b011 << 24;
+const AP_READ_ONLY: u32 = 0b110 << 24;
+
+// link.ldのFLASHと合わせる
+const FLASH_BASE: usize = 0x0800_0000;
+const FLASH_SIZE: usize = 256 * 1024;
+
+// 番号の大きいリージョンが優先される
+const REGION_CODE: u32 = 0;
+const REGION_SHARED: u32 = 1;
+const REGION_STACK: u32 = 2;
+const REGION_GUARD: u32 = 3;
+const REGION_GRANT: u32 = 4;
+pub const NUM_GRANTS: usize = 4;
+
+// スタックの下端のアプリからアクセスできない領域
+pub const GUARD_SIZE: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    base: u32,
+    rasr: u32,
+}
+
+impl Region {
+    // アプリにアクセスを許可する領域
+    // サイズは32バイト以上の2の累乗で、ベースアドレスはサイズの倍数でなければならない
+    pub fn new(base: usize, size: usize, access: Access) -> Option<Self> {
+        if size < 32 || !size.is_power_of_two() || !base.is_multiple_of(size) {
+            return None;
+        }
+        let ap = match access {
+            Access::ReadOnly => AP_PRIV_RW_USER_RO,
+            Access::ReadWrite => AP_FULL,
+        };
+        Some(Region {
+            base: base as u32,
+            rasr: size_field(size) | ap | RASR_XN | RASR_NORMAL | RASR_ENABLE,
+        })
+    }
+
+    // アプリのスタック
+    // 境界が揃っていないスタックは、含まれる最小のリージョンからサブリージョンを無効にして近づける
+    pub fn stack(base: usize, size: usize) -> Self {
+        covering(base, size, AP_FULL | RASR_XN)
+    }
+
+    // スタックの下端。あふれるとMemManageフォールトになる
+    pub fn guard(stack_base: usize) -> Self {
+        let base = stack_base.next_multiple_of(GUARD_SIZE);
+        Region {
+            base: base as u32,
+            rasr: size_field(GUARD_SIZE) | AP_PRIV_RW | RASR_XN | RASR_NORMAL | RASR_ENABLE,
+        }
+    }
+
+    fn size(&self) -> usize {
+        1 << (((self.rasr >> 1) & 0x1F) + 1)
+    }
+
+    // [start, end)に重なるサブリージョンのビット
+    // 256バイト未満のリージョンはサブリージョンに分けられないので、全体を1つとして扱う
+    fn subregions(&self, start: usize, end: usize) -> u32 {
+        let base = self.base as usize;
+        let size = self.size();
+        if end <= base || start >= base + size {
+            return 0;
+        }
+        if size < 256 {
+            return 0xFF;
+        }
+        let sub = size / 8;
+        let first = (start.max(base) - base) / sub;
+        let last = (end.min(base + size) - 1 - base) / sub;
+        (first..=last).fold(0, |mask, i| mask | (1 << i))
+    }
+
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.subregions(start, end) & !(self.rasr >> 8) & 0xFF != 0
+    }
+
+    fn contains(&self, start: usize, end: usize) -> bool {
+        let base = self.base as usize;
+        start >= base && end <= base + self.size() && self.subregions(start, end) & (self.rasr >> 8) & 0xFF == 0
+    }
+
+    // 非特権モードでaccessできるか
+    fn allows(&self, access: Access) -> bool {
+        match self.rasr & (0b111 << 24) {
+            AP_FULL => true,
+            AP_PRIV_RW_USER_RO | AP_READ_ONLY => access == Access::ReadOnly,
+            _ => false,
+        }
+    }
+}
+
+// [start, start+len)を含む最小のリージョン
+fn covering(start: usize, len: usize, attrs: u32) -> Region {
+    let end = start + len;
+    let mut size = len.next_power_of_two().max(32);
+    while start & !(size - 1) != (end - 1) & !(size - 1) {
+        size <<= 1;
+    }
+    let base = start & !(size - 1);
+
+    // 256バイト以上のリージョンは8つのサブリージョンに分けて個別に無効にできる
+    let mut srd = 0;
+    if size >= 256 {
+        let sub = size / 8;
+        for i in 0..8 {
+            let sub_start = base + i * sub;
+            if sub_start + sub <= start || sub_start >= end {
+                srd |= 1 << i;
+            }
+        }
+    }
+    Region {
+        base: base as u32,
+        rasr: (srd << 8) | size_field(size) | attrs | RASR_NORMAL | RASR_ENABLE,
+    }
+}
+
+// SIZEフィールドはlog2(size)-1
+fn size_field(size: usize) -> u32 {
+    (size.trailing_zeros() - 1) << 1
+}
+
+// フラッシュ全体。アプリからは読み出しと実行だけできる
+fn code_region() -> Region {
+    Region {
+        base: FLASH_BASE as u32,
+        rasr: size_field(FLASH_SIZE) | AP_READ_ONLY | RASR_NORMAL | RASR_ENABLE,
+    }
+}
+
+// link.ldの.shared。アプリからは読み出しだけできる
+fn shared_region() -> Region {
+    extern "C" {
+        static mut _sshared: u8;
+        static mut _eshared: u8;
+    }
+
+    let shared_start = &raw const _sshared as usize;
+    let shared_len = &raw const _eshared as usize - shared_start;
+    covering(shared_start, shared_len.max(1), AP_PRIV_RW_USER_RO | RASR_XN)
+}
+
+// コードと共有データのリージョンを設定してMPUを有効にする
+// アプリごとのリージョンはload()で切り替える
+pub fn init() {
+    unsafe {
+        write_region(REGION_CODE, code_region());
+        write_region(REGION_SHARED, shared_region());
+        write_volatile(MPU_CTRL_ADDR as *mut u32, MPU_CTRL_ENABLE | MPU_CTRL_PRIVDEFENA);
+        #[cfg(not(test))]
+        asm!("dsb", "isb");
+    }
+}
+
+// 次に実行するアプリのスタック・ガード・許可された領域に切り替える
+pub fn load(stack: Region, guard: Region, grants: &[Option<Region>; NUM_GRANTS]) {
+    unsafe {
+        write_region(REGION_STACK, stack);
+        write_region(REGION_GUARD, guard);
+        for (i, grant) in grants.iter().enumerate() {
+            match grant {
+                Some(region) => write_region(REGION_GRANT + i as u32, *region),
+                None => disable_region(REGION_GRANT + i as u32),
+            }
+        }
+        #[cfg(not(test))]
+        asm!("dsb", "isb");
+    }
+}
+
+// load()と同じリージョンを設定したときに、アプリが[start, start+len)にaccessできるか
+// 番号の大きいリージョンが優先されるので、範囲に重なる最も番号の大きいリージョンが範囲全体を含んで許可している場合だけtrue
+pub fn permits(
+    stack: Region,
+    guard: Region,
+    grants: &[Option<Region>; NUM_GRANTS],
+    start: usize,
+    len: usize,
+    access: Access,
+) -> bool {
+    let Some(end) = start.checked_add(len.max(1)) else {
+        return false;
+    };
+    let regions = [Some(code_region()), Some(shared_region()), Some(stack), Some(guard)];
+    regions
+        .iter()
+        .chain(grants.iter())
+        .rev()
+        .flatten()
+        .find(|region| region.overlaps(start, end))
+        .is_some_and(|region| region.contains(start, end) && region.allows(access))
+}
+
+// [start, start+len)がフラッシュの中にあるか
+pub fn in_code(start: usize, len: usize) -> bool {
+    start.checked_add(len).is_some_and(|end| code_region().contains(start, end))
+}
+
+unsafe fn write_region(number: u32, region: Region) {
+    write_volatile(MPU_RBAR_ADDR as *mut u32, region.base | RBAR_VALID | number);
+    write_volatile(MPU_RASR_ADDR as *mut u32, region.rasr);
+}
+
+unsafe fn disable_region(number: u32) {
+    write_volatile(MPU_RBAR_ADDR as *mut u32, RBAR_VALID | number);
+    write_volatile(MPU_RASR_ADDR as *mut u32, 0);
+}
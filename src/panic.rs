@@ -0,0 +1,93 @@
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU8, Ordering};
+use cortex_m_semihosting::{debug, hprintln};
+use crate::scb;
+use crate::scheduler;
+use crate::syscall;
+
+// パニックしたときの動作
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanicAction {
+    // 割り込みを止めて停止する
+    Halt,
+    // AIRCRのSYSRESETREQでリセットする
+    Reset,
+    // semihostingのexitでQEMUを終了する
+    SemihostingExit,
+    // パニックしたアプリだけを終了させる。カーネルのパニックでは停止する
+    KillProcess,
+}
+
+static ACTION: AtomicU8 = AtomicU8::new(PanicAction::Halt as u8);
+
+pub fn set_action(action: PanicAction) {
+    ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+pub fn action() -> PanicAction {
+    match ACTION.load(Ordering::Relaxed) {
+        1 => PanicAction::Reset,
+        2 => PanicAction::SemihostingExit,
+        3 => PanicAction::KillProcess,
+        _ => PanicAction::Halt,
+    }
+}
+
+// システム全体を止める動作を行う
+pub fn abort(action: PanicAction) -> ! {
+    match action {
+        PanicAction::Reset => scb::system_reset(),
+        PanicAction::SemihostingExit => {
+            debug::exit(debug::EXIT_FAILURE);
+        }
+        PanicAction::Halt | PanicAction::KillProcess => {}
+    }
+    hprintln!("[Kernel]: system halted");
+    #[cfg(not(test))]
+    unsafe {
+        asm!("cpsid i");
+    }
+    loop {
+        #[cfg(not(test))]
+        unsafe {
+            asm!("wfi");
+        }
+    }
+}
+
+// 非特権のスレッドモード、つまりアプリの中でパニックしたか
+#[cfg(not(test))]
+fn in_process() -> bool {
+    let control: u32;
+    let ipsr: u32;
+    unsafe {
+        asm!("mrs {}, CONTROL", out(reg) control);
+        asm!("mrs {}, IPSR", out(reg) ipsr);
+    }
+    ipsr & 0x1FF == 0 && control & 1 != 0
+}
+
+// アプリはカーネルのメモリにアクセスできないので、表示と後の処理をシステムコールで行う
+// ホストでのテストではstdのパニックハンドラを使う
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo<'_>) -> ! {
+    if in_process() {
+        match info.location() {
+            Some(location) => crate::println!("panicked at {}: {}", location, info.message()),
+            None => crate::println!("panicked: {}", info.message()),
+        }
+        syscall::panic();
+    }
+
+    match info.location() {
+        Some(location) => hprintln!("[Kernel]: panicked at {}: {}", location, info.message()),
+        None => hprintln!("[Kernel]: panicked: {}", info.message()),
+    }
+    if let Some((pid, name)) = scheduler::current_process() {
+        hprintln!("[Kernel]: while running process {} ({})", pid, name);
+    }
+    abort(action())
+}
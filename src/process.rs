@@ -1,5 +1,11 @@
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
 use core::arch::asm;
+use core::fmt;
 use core::marker::PhantomData;
+use core::ptr::NonNull;
+use crate::linked_list::{LinkedList, ListItem};
+use crate::mpu::{self, Access, Region, GUARD_SIZE, NUM_GRANTS};
+use crate::syscall::{self, Error};
 
 #[repr(C)]
 pub struct ContextFrame {
@@ -13,50 +19,549 @@ pub struct ContextFrame {
     pub xpsr: u32,
 }
 
+// アプリからカーネルに戻ってきた理由
+// SVCall/PendSVがカーネル側のスタックに積まれたr0に書き込む
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwitchReason {
+    Syscall,
+    Preempted,
+    Fault,
+}
+
+// 例外から戻るときにスレッドモード・PSPを使う
+// アプリがFPUを使うと拡張フレーム(0xFFFF_FFED)になる
+const EXC_RETURN_THREAD_PSP: u32 = 0xFFFF_FFFD;
+const EXC_RETURN_FTYPE: u32 = 1 << 4;
+
+// カーネルが退避するレジスタ
+// s16-s31はアプリがFPUを使っている場合だけ退避する
+#[repr(C)]
+struct SavedRegs {
+    r4_r11: [u32; 8],
+    s16_s31: [u32; 16],
+}
+
+pub type Pid = u32;
+
+// アプリのエントリポイント。引数はr0で渡す
+// 戻り値は終了コードになる
+pub type EntryPoint = extern "C" fn(usize) -> i32;
+
+// 優先度は0が最も高い
+pub const NUM_PRIORITIES: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessState {
+    Ready,
+    Running,
+    Blocked,
+    Sleeping,
+    Exited,
+}
+
+impl fmt::Display for ProcessState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ProcessState::Ready => "Ready",
+            ProcessState::Running => "Running",
+            ProcessState::Blocked => "Blocked",
+            ProcessState::Sleeping => "Sleeping",
+            ProcessState::Exited => "Exited",
+        };
+        f.pad(s)
+    }
+}
+
+// 周期タスクのパラメータ(単位はtick)
+#[derive(Clone, Copy, Debug)]
+pub struct PeriodicTask {
+    pub period: u32,
+    // リリースからの相対デッドライン
+    pub deadline: u32,
+    // 1ジョブあたりの最悪実行時間
+    pub wcet: u32,
+}
+
+// フォールトで停止したアプリを再起動するかどうか
+#[derive(Clone, Copy, Debug)]
+pub enum RestartPolicy {
+    Never,
+    // 毎回すぐに再起動する
+    Always,
+    // max_restarts回まで再起動する
+    // 再起動を待つtick数は初回がbackoffで、再起動するたびに倍になる
+    UpTo { max_restarts: u32, backoff: u32 },
+}
+
+// spawnでヒープから確保したスタック
+// MPUのリージョンにできるように、2の累乗のサイズで同じ境界に揃える
+pub struct HeapStack {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl HeapStack {
+    pub fn new(size: usize) -> Option<Self> {
+        let layout = Layout::from_size_align(size, size).ok()?;
+        let ptr = NonNull::new(unsafe { alloc_zeroed(layout) })?;
+        Some(HeapStack { ptr, layout })
+    }
+}
+
+impl Drop for HeapStack {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
+// スタックの底に置く番兵。書き換えられていればスタックがあふれている
+const STACK_CANARY: u32 = 0xC0DE_CAFE;
+const STACK_CANARY_WORDS: usize = 4;
+// 番兵より上の未使用のスタックを埋めておき、書き換えられた範囲から最大使用量を求める
+const STACK_PAINT: u32 = 0xA5A5_A5A5;
+
+// 再起動されずに終了したアプリの終了コード
+pub const EXIT_CODE_FAULT: i32 = -128;
+// パニックして終了させられたアプリの終了コード
+pub const EXIT_CODE_PANIC: i32 = -101;
+
 pub struct Process<'a> {
+    pid: Pid,
+    name: &'static str,
+    stack_base: usize,
+    stack_size: usize,
+    entry: EntryPoint,
+    arg: usize,
     sp: usize,
-    regs: [u32; 8],
+    regs: SavedRegs,
+    exc_return: u32,
+    state: ProcessState,
+    wake_at: u64,
+    priority: u8,
+    // 絶対デッドライン(tick)。EDFスケジューリングで使う
+    deadline: u64,
+    periodic: Option<PeriodicTask>,
+    release_at: u64,
+    // 現在のジョブで残っている実行時間
+    budget: u32,
+    deadline_missed: bool,
+    exit_code: i32,
+    // Blocked状態のときに終了を待っているアプリ
+    waiting_for: Option<Pid>,
+    // このアプリの終了を待っているアプリ
+    waiters: LinkedList<'a, Process<'a>>,
+    heap_stack: Option<HeapStack>,
+    // MPUで許可するメモリ
+    stack_region: Region,
+    guard_region: Region,
+    grants: [Option<Region>; NUM_GRANTS],
+    restart_policy: RestartPolicy,
+    restarts: u32,
     marker: PhantomData<&'a u8>,
 }
 
 impl<'a> Process<'a> {
-    pub fn new(stack: *mut u8, stack_len: &usize, app_main: extern "C" fn() -> !) -> Self {
-        let sp = (stack as *const u8 as usize) + stack_len - 0x20;
-        let context_frame: &mut ContextFrame = unsafe { &mut *(sp as *mut ContextFrame) };
-        context_frame.r0 = 0;
+    pub fn new(
+        name: &'static str,
+        stack: *mut u8,
+        stack_len: &usize,
+        app_main: EntryPoint,
+        arg: usize,
+        priority: u8,
+    ) -> Self {
+        assert!((priority as usize) < NUM_PRIORITIES);
+        let mut process = Process {
+            pid: 0,
+            name,
+            stack_base: stack as usize,
+            stack_size: *stack_len,
+            entry: app_main,
+            arg,
+            sp: 0,
+            regs: SavedRegs {
+                r4_r11: [0; 8],
+                s16_s31: [0; 16],
+            },
+            exc_return: 0,
+            state: ProcessState::Ready,
+            wake_at: 0,
+            priority,
+            deadline: u64::MAX,
+            periodic: None,
+            release_at: 0,
+            budget: 0,
+            deadline_missed: false,
+            exit_code: 0,
+            waiting_for: None,
+            waiters: LinkedList::new(),
+            heap_stack: None,
+            stack_region: Region::stack(stack as usize, *stack_len),
+            guard_region: Region::guard(stack as usize),
+            grants: [None; NUM_GRANTS],
+            restart_policy: RestartPolicy::Never,
+            restarts: 0,
+            marker: PhantomData,
+        };
+        process.init_context();
+        process
+    }
+
+    // スタックの先頭に最初の例外フレームを作る
+    fn init_context(&mut self) {
+        self.sp = self.stack_base + self.stack_size - 0x20;
+        self.regs = SavedRegs {
+            r4_r11: [0; 8],
+            s16_s31: [0; 16],
+        };
+        self.exc_return = EXC_RETURN_THREAD_PSP;
+
+        let canary = self.stack_base as *mut u32;
+        for i in 0..STACK_CANARY_WORDS {
+            unsafe { canary.add(i).write_volatile(STACK_CANARY) };
+        }
+        let paint_words = (self.sp - self.stack_base) / 4;
+        for i in STACK_CANARY_WORDS..paint_words {
+            unsafe { canary.add(i).write_volatile(STACK_PAINT) };
+        }
+
+        let arg = self.arg;
+        let entry = self.entry;
+        let context_frame = self.context_frame();
+        context_frame.r0 = arg as u32;
         context_frame.r1 = 0;
         context_frame.r2 = 0;
         context_frame.r3 = 0;
         context_frame.r12 = 0;
-        context_frame.lr = 0;
-        context_frame.return_addr = app_main as u32;
+        // エントリポイントから戻るとexitシステムコールを呼ぶ
+        let trampoline: extern "C" fn(i32) -> ! = exit_trampoline;
+        context_frame.lr = trampoline as usize as u32;
+        context_frame.return_addr = entry as usize as u32;
         context_frame.xpsr = 0x0100_0000;
+    }
 
-        Process {
-            sp,
-            regs: [0; 8],
-            marker: PhantomData,
+    // ヒープに確保したスタックでアプリを作る
+    pub fn with_heap_stack(
+        name: &'static str,
+        stack: HeapStack,
+        app_main: EntryPoint,
+        arg: usize,
+        priority: u8,
+    ) -> Self {
+        let stack_len = stack.layout.size();
+        let mut process = Process::new(name, stack.ptr.as_ptr(), &stack_len, app_main, arg, priority);
+        process.heap_stack = Some(stack);
+        process
+    }
+
+    pub fn is_heap_allocated(&self) -> bool {
+        self.heap_stack.is_some()
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn set_pid(&mut self, pid: Pid) {
+        self.pid = pid;
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn stack_size(&self) -> usize {
+        self.stack_size
+    }
+
+    // 最後にカーネルに戻ってきた時点でのスタック使用量
+    pub fn stack_usage(&self) -> usize {
+        (self.stack_base + self.stack_size).saturating_sub(self.sp)
+    }
+
+    // 起動(再起動)してから最も深く使ったスタックのバイト数
+    // 塗った模様が残っていない最も低いアドレスから数える
+    pub fn stack_peak(&self) -> usize {
+        let words = self.stack_base as *const u32;
+        let untouched = (STACK_CANARY_WORDS..self.stack_size / 4)
+            .take_while(|&i| unsafe { words.add(i).read_volatile() } == STACK_PAINT)
+            .count();
+        self.stack_size - (STACK_CANARY_WORDS + untouched) * 4
+    }
+
+    pub fn stack_base(&self) -> usize {
+        self.stack_base
+    }
+
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    // スタック以外にアクセスを許可する領域を追加する
+    pub fn grant(&mut self, region: Region) -> Result<(), Error> {
+        let slot = self.grants.iter_mut().find(|g| g.is_none()).ok_or(Error::NoMem)?;
+        *slot = Some(region);
+        Ok(())
+    }
+
+    // このアプリを実行できるようにMPUを切り替える
+    pub fn load_mpu(&self) {
+        mpu::load(self.stack_region, self.guard_region, &self.grants);
+    }
+
+    // アプリから渡された[addr, addr+len)が、アプリ自身がaccessできる範囲にあるか
+    // カーネルは特権モードでMPUに関係なくアクセスできるので、ポインタを使う前に必ず確かめる
+    pub fn check_access(&self, addr: usize, len: usize, access: Access) -> Result<(), Error> {
+        if addr == 0 || !mpu::permits(self.stack_region, self.guard_region, &self.grants, addr, len, access) {
+            return Err(Error::Inval);
+        }
+        Ok(())
+    }
+
+    // 番兵が書き換えられているか、spが番兵より下にある
+    // spがスタックの底のガード領域(またはその下)にある
+    // ガード領域への書き込みはMemManageフォールトになるので、例外フレームは積まれていない
+    pub fn sp_in_guard(&self) -> bool {
+        self.sp < self.stack_base.next_multiple_of(GUARD_SIZE) + GUARD_SIZE
+    }
+
+    // 例外フレーム(sp..sp+32)がスタックの範囲内にあるか
+    pub fn context_frame_valid(&self) -> bool {
+        self.sp >= self.stack_base
+            && self
+                .sp
+                .checked_add(core::mem::size_of::<ContextFrame>())
+                .is_some_and(|end| end <= self.stack_base + self.stack_size)
+    }
+
+    pub fn stack_overflowed(&self) -> bool {
+        let canary = self.stack_base as *const u32;
+        self.sp < self.stack_base + STACK_CANARY_WORDS * 4
+            || (0..STACK_CANARY_WORDS).any(|i| unsafe { canary.add(i).read_volatile() } != STACK_CANARY)
+    }
+
+    pub fn state(&self) -> ProcessState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: ProcessState) {
+        self.state = state;
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    // 周期タスクにして、release_atに最初のジョブをリリースする
+    pub fn set_periodic(&mut self, task: PeriodicTask, release_at: u64) {
+        self.periodic = Some(task);
+        self.release(release_at);
+    }
+
+    pub fn periodic(&self) -> Option<PeriodicTask> {
+        self.periodic
+    }
+
+    pub fn release_at(&self) -> u64 {
+        self.release_at
+    }
+
+    // 新しいジョブのデッドラインと実行時間を設定する
+    pub fn release(&mut self, release_at: u64) {
+        if let Some(task) = self.periodic {
+            self.release_at = release_at;
+            self.deadline = release_at + task.deadline as u64;
+            self.budget = task.wcet;
+            self.deadline_missed = false;
+        }
+    }
+
+    // 実行時間を1tick消費する。WCETを使い切ったときにtrueを返す
+    pub fn consume_budget(&mut self) -> bool {
+        if self.periodic.is_none() || self.budget == 0 {
+            return false;
         }
+        self.budget -= 1;
+        self.budget == 0
     }
 
-    pub fn exec(&mut self) {
+    // 初めてデッドラインを過ぎたことを検出したときにtrueを返す
+    pub fn check_deadline(&mut self, now: u64) -> bool {
+        if self.periodic.is_none() || self.deadline_missed || now <= self.deadline {
+            return false;
+        }
+        self.deadline_missed = true;
+        true
+    }
+
+    pub fn exit(&mut self, code: i32) {
+        self.exit_code = code;
+        self.state = ProcessState::Exited;
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code
+    }
+
+    pub fn set_restart_policy(&mut self, policy: RestartPolicy) {
+        self.restart_policy = policy;
+    }
+
+    // 再起動する場合は、再起動までに待つtick数を返す
+    pub fn restart_delay(&self) -> Option<u32> {
+        match self.restart_policy {
+            RestartPolicy::Never => None,
+            RestartPolicy::Always => Some(0),
+            RestartPolicy::UpTo { max_restarts, backoff } => {
+                (self.restarts < max_restarts).then(|| backoff.saturating_mul(1 << self.restarts.min(31)))
+            }
+        }
+    }
+
+    // 元のエントリポイントとスタックからやり直す
+    pub fn restart(&mut self) {
+        self.restarts += 1;
+        self.init_context();
+        self.state = ProcessState::Ready;
+    }
+
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    // pidのアプリが終了するまでブロックする
+    pub fn wait_for(&mut self, pid: Pid) {
+        self.waiting_for = Some(pid);
+        self.state = ProcessState::Blocked;
+    }
+
+    pub fn waiting_for(&self) -> Option<Pid> {
+        self.waiting_for
+    }
+
+    pub fn add_waiter(&mut self, item: &'a mut ListItem<'a, Process<'a>>) {
+        self.waiters.push(item);
+    }
+
+    pub fn has_waiters(&self) -> bool {
+        !self.waiters.is_empty()
+    }
+
+    pub fn pop_waiter(&mut self) -> Option<&'a mut ListItem<'a, Process<'a>>> {
+        self.waiters.pop()
+    }
+
+    // 待っていたアプリが終了したので、waitの出力引数(r1)に終了コードを書き込む
+    pub fn complete_wait(&mut self, code: i32) {
+        self.waiting_for = None;
+        let out = self.context_frame().r1 as usize;
+        if out.is_multiple_of(align_of::<i32>()) && self.check_access(out, size_of::<i32>(), Access::ReadWrite).is_ok() {
+            unsafe { (out as *mut i32).write_volatile(code) };
+        }
+    }
+
+    // 指定したtickまでスリープ状態にする
+    pub fn sleep_until(&mut self, tick: u64) {
+        self.wake_at = tick;
+        self.state = ProcessState::Sleeping;
+    }
+
+    pub fn wake_at(&self) -> u64 {
+        self.wake_at
+    }
+
+    // svcかPendSVでカーネルに戻ってくるまでアプリを実行する
+    // r6とr7はclobberに指定できないので、カーネル側のr4-r11とs16-s31はスタックに退避しておく
+    pub fn exec(&mut self) -> SwitchReason {
+        let reason: u32;
+        // ホストでのテストではアプリを実行できない
+        #[cfg(test)]
+        unreachable!("exec {} on the host", self.name);
+        #[cfg(not(test))]
         unsafe {
             asm!(
-                "msr psp, {sp}",
+                "push {{r4-r11}}",
+                "vpush {{s16-s31}}",
+                "msr psp, r2",
                 "ldmia r1, {{r4-r11}}",
+                "tst r3, {ftype}",
+                "itt eq",
+                "addeq r12, r1, #32",
+                "vldmiaeq r12, {{s16-s31}}",
                 "svc 0",
-                "stmia {regs}, {{r4-r11}}",
-                "mrs {sp}, psp",
-                sp = inout(reg) self.sp,
-                regs = in(reg) self.regs.as_ptr(),
-                out("r4") _,
-                out("r5") _,
-                out("r8") _,
-                out("r9") _,
-                out("r10") _,
-                out("r11") _,
-                options(nostack, preserves_flags),
+                "stmia r1, {{r4-r11}}",
+                "tst r3, {ftype}",
+                "itt eq",
+                "addeq r12, r1, #32",
+                "vstmiaeq r12, {{s16-s31}}",
+                "mrs r2, psp",
+                "vpop {{s16-s31}}",
+                "pop {{r4-r11}}",
+                ftype = const EXC_RETURN_FTYPE,
+                out("r0") reason,
+                in("r1") &mut self.regs as *mut SavedRegs,
+                inout("r2") self.sp,
+                inout("r3") self.exc_return,
+                out("r12") _,
             );
         }
+        match reason {
+            1 => SwitchReason::Syscall,
+            3 => SwitchReason::Fault,
+            _ => SwitchReason::Preempted,
+        }
     }
-}
\ No newline at end of file
+
+    // アプリのスタックに積まれた例外フレーム
+    pub fn context_frame(&mut self) -> &mut ContextFrame {
+        unsafe { &mut *(self.sp as *mut ContextFrame) }
+    }
+
+    // 直前に実行したsvc命令の即値を取り出す
+    pub fn syscall_number(&mut self) -> u8 {
+        let svc_addr = self.context_frame().return_addr - 2;
+        unsafe { (svc_addr as *const u16).read_volatile() as u8 }
+    }
+}
+
+// エントリポイントの戻り値(r0)をそのまま終了コードとして渡す
+extern "C" fn exit_trampoline(code: i32) -> ! {
+    syscall::exit(code)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Process, STACK_CANARY_WORDS};
+
+    extern "C" fn entry(_arg: usize) -> i32 {
+        0
+    }
+
+    const STACK_LEN: usize = 256;
+
+    #[test]
+    fn test_stack_overflowed() {
+        let mut stack = [0u32; STACK_LEN / 4];
+        let base = stack.as_mut_ptr();
+        let process = Process::new("test", base as *mut u8, &STACK_LEN, entry, 0, 0);
+        assert!(!process.stack_overflowed());
+
+        // 番兵より上を使っただけではあふれていない
+        unsafe { base.add(STACK_CANARY_WORDS).write_volatile(0) };
+        assert!(!process.stack_overflowed());
+        assert_eq!(STACK_LEN - STACK_CANARY_WORDS * 4, process.stack_peak());
+
+        // 番兵が書き換えられた
+        unsafe { base.write_volatile(0) };
+        assert!(process.stack_overflowed());
+
+        // 番兵が残っていても、spが番兵の中を指していればあふれている
+        let mut process2 = Process::new("test", base as *mut u8, &STACK_LEN, entry, 0, 0);
+        assert!(!process2.stack_overflowed());
+        process2.sp = base as usize + 8;
+        assert!(process2.stack_overflowed());
+    }
+}
@@ -0,0 +1,190 @@
+use core::ptr::NonNull;
+use crate::process::{Pid, Process, ProcessState};
+use crate::linked_list::ListItem;
+
+pub const MAX_PROCESSES: usize = 16;
+
+// プロセステーブルから参照できるアプリの情報
+#[derive(Clone, Copy, Debug)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub name: &'static str,
+    pub state: ProcessState,
+    pub priority: u8,
+    pub stack_usage: usize,
+    pub stack_peak: usize,
+    pub stack_size: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Slot<'a> {
+    Free,
+    Live(NonNull<ListItem<'a, Process<'a>>>),
+    // 終了したがwaitされていないアプリの終了コード
+    Zombie(i32),
+}
+
+// PIDからアプリを引くためのテーブル
+// PIDは1からMAX_PROCESSESまでで、PID-1をインデックスとして使う
+// 終了したアプリのPIDは、waitで終了コードが回収されるまで解放しない
+pub struct ProcessTable<'a> {
+    slots: [Slot<'a>; MAX_PROCESSES],
+    last_pid: Pid,
+}
+
+impl<'a> ProcessTable<'a> {
+    pub fn new() -> Self {
+        ProcessTable {
+            slots: [Slot::Free; MAX_PROCESSES],
+            last_pid: 0,
+        }
+    }
+
+    // 空いているPIDを割り当てて登録する
+    // 解放されたPIDがすぐに再利用されないように、前回割り当てたPIDの次から探す
+    pub fn alloc(&mut self, item: &mut ListItem<'a, Process<'a>>) -> Option<Pid> {
+        let start = self.last_pid as usize;
+        let index = (0..MAX_PROCESSES)
+            .map(|i| (start + i) % MAX_PROCESSES)
+            .find(|&i| matches!(self.slots[i], Slot::Free))?;
+
+        let pid = index as Pid + 1;
+        item.set_pid(pid);
+        self.slots[index] = Slot::Live(NonNull::from(item));
+        self.last_pid = pid;
+        Some(pid)
+    }
+
+    pub fn free(&mut self, pid: Pid) {
+        if let Some(index) = Self::index(pid) {
+            self.slots[index] = Slot::Free;
+        }
+    }
+
+    // 終了コードを残してアプリをテーブルから外す
+    pub fn set_exited(&mut self, pid: Pid, code: i32) {
+        if let Some(index) = Self::index(pid) {
+            self.slots[index] = Slot::Zombie(code);
+        }
+    }
+
+    pub fn get_mut(&mut self, pid: Pid) -> Option<&mut Process<'a>> {
+        match Self::index(pid).map(|i| self.slots[i]) {
+            Some(Slot::Live(ptr)) => Some(unsafe { &mut *ptr.as_ptr() }),
+            _ => None,
+        }
+    }
+
+    // 終了済みのアプリの終了コードを回収してPIDを解放する
+    pub fn reap(&mut self, pid: Pid) -> Option<i32> {
+        let index = Self::index(pid)?;
+        match self.slots[index] {
+            Slot::Zombie(code) => {
+                self.slots[index] = Slot::Free;
+                Some(code)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn for_each_mut(&mut self, mut f: impl FnMut(&mut Process<'a>)) {
+        for slot in self.slots.iter() {
+            if let Slot::Live(ptr) = slot {
+                f(unsafe { &mut *ptr.as_ptr() });
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ProcessInfo> + '_ {
+        self.slots.iter().filter_map(|slot| {
+            let Slot::Live(ptr) = slot else {
+                return None;
+            };
+            let process: &Process = unsafe { ptr.as_ref() };
+            Some(ProcessInfo {
+                pid: process.pid(),
+                name: process.name(),
+                state: process.state(),
+                priority: process.priority(),
+                stack_usage: process.stack_usage(),
+                stack_peak: process.stack_peak(),
+                stack_size: process.stack_size(),
+            })
+        })
+    }
+
+    fn index(pid: Pid) -> Option<usize> {
+        let index = (pid as usize).checked_sub(1)?;
+        (index < MAX_PROCESSES).then_some(index)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+    use super::{ProcessTable, MAX_PROCESSES};
+    use crate::linked_list::ListItem;
+    use crate::process::{Pid, Process};
+
+    extern "C" fn entry(_arg: usize) -> i32 {
+        0
+    }
+
+    const STACK_LEN: usize = 256;
+
+    // スタックの番兵と最初の例外フレームを書き込むので、ワード境界に揃えたバッファを使う
+    fn item(stack: &mut [u32; STACK_LEN / 4]) -> ListItem<'static, Process<'static>> {
+        ListItem::new(Process::new("test", stack.as_mut_ptr() as *mut u8, &STACK_LEN, entry, 0, 0))
+    }
+
+    #[test]
+    fn test_alloc() {
+        let mut stacks = [[0u32; STACK_LEN / 4]; MAX_PROCESSES + 1];
+        let mut items: Vec<_> = stacks.iter_mut().map(item).collect();
+        let (last, items) = items.split_last_mut().unwrap();
+        let mut table = ProcessTable::new();
+
+        assert_eq!(Some(1), table.alloc(&mut items[0]));
+        assert_eq!(Some(2), table.alloc(&mut items[1]));
+        assert_eq!(2, items[1].pid());
+
+        // 解放したPIDはすぐには再利用しない
+        table.free(1);
+        assert_eq!(Some(3), table.alloc(&mut items[2]));
+        for (i, item) in items[3..].iter_mut().enumerate() {
+            assert_eq!(Some(i as Pid + 4), table.alloc(item));
+        }
+
+        // 一周したら空いているPIDを使う
+        assert_eq!(Some(1), table.alloc(&mut items[0]));
+        assert_eq!(None, table.alloc(last));
+        assert_eq!(MAX_PROCESSES, table.iter().count());
+        assert!(table.get_mut(0).is_none());
+        assert!(table.get_mut(MAX_PROCESSES as Pid + 1).is_none());
+    }
+
+    #[test]
+    fn test_reap() {
+        let mut stacks = [[0u32; STACK_LEN / 4]; MAX_PROCESSES + 1];
+        let mut items: Vec<_> = stacks.iter_mut().map(item).collect();
+        let (last, items) = items.split_last_mut().unwrap();
+        let mut table = ProcessTable::new();
+        for item in items.iter_mut() {
+            table.alloc(item).unwrap();
+        }
+
+        // 実行中のアプリは回収できない
+        assert_eq!(None, table.reap(2));
+
+        // 終了したアプリはテーブルから外れるが、回収されるまでPIDは使われない
+        table.set_exited(2, -1);
+        assert!(table.get_mut(2).is_none());
+        assert_eq!(MAX_PROCESSES - 1, table.iter().count());
+        assert_eq!(None, table.alloc(last));
+
+        assert_eq!(Some(-1), table.reap(2));
+        assert_eq!(None, table.reap(2));
+        assert_eq!(Some(2), table.alloc(last));
+        assert_eq!(None, table.reap(0));
+    }
+}
@@ -0,0 +1,83 @@
+use core::arch::asm;
+use core::ptr::{read_volatile, write_volatile};
+
+const ICSR_ADDR: usize = 0xE000_ED04;
+const AIRCR_ADDR: usize = 0xE000_ED0C;
+const SCR_ADDR: usize = 0xE000_ED10;
+const SHPR3_ADDR: usize = 0xE000_ED20;
+const SHCSR_ADDR: usize = 0xE000_ED24;
+const CPACR_ADDR: usize = 0xE000_ED88;
+const FPCCR_ADDR: usize = 0xE000_EF34;
+
+const ICSR_PENDSVSET: u32 = 1 << 28;
+const SCR_SLEEPDEEP: u32 = 1 << 2;
+// AIRCRへの書き込みにはVECTKEYが必要
+const AIRCR_VECTKEY: u32 = 0x05FA << 16;
+const AIRCR_SYSRESETREQ: u32 = 1 << 2;
+const SHCSR_MEMFAULTENA: u32 = 1 << 16;
+const SHCSR_BUSFAULTENA: u32 = 1 << 17;
+const SHCSR_USGFAULTENA: u32 = 1 << 18;
+// CP10とCP11をフルアクセスにする
+const CPACR_CP10_CP11: u32 = 0xF << 20;
+const FPCCR_ASPEN: u32 = 1 << 31;
+const FPCCR_LSPEN: u32 = 1 << 30;
+
+pub fn init() {
+    unsafe {
+        // PendSVの優先度を最低にして、他の割り込みが終わってからコンテキストスイッチさせる
+        let shpr3 = read_volatile(SHPR3_ADDR as *const u32);
+        write_volatile(SHPR3_ADDR as *mut u32, shpr3 | (0xFF << 16));
+    }
+}
+
+// MemManage・BusFault・UsageFaultをHardFaultにエスカレートさせずに、それぞれのハンドラで受け取る
+pub fn enable_fault_handlers() {
+    unsafe {
+        let shcsr = read_volatile(SHCSR_ADDR as *const u32);
+        write_volatile(SHCSR_ADDR as *mut u32, shcsr | SHCSR_MEMFAULTENA | SHCSR_BUSFAULTENA | SHCSR_USGFAULTENA);
+    }
+}
+
+pub fn set_pendsv() {
+    unsafe {
+        write_volatile(ICSR_ADDR as *mut u32, ICSR_PENDSVSET);
+    }
+}
+
+// システムをリセットする。RAMの内容は残る
+pub fn system_reset() -> ! {
+    unsafe {
+        #[cfg(not(test))]
+        asm!("dsb");
+        let aircr = read_volatile(AIRCR_ADDR as *const u32);
+        // PRIGROUPは残す
+        write_volatile(AIRCR_ADDR as *mut u32, AIRCR_VECTKEY | (aircr & (0x7 << 8)) | AIRCR_SYSRESETREQ);
+        #[cfg(not(test))]
+        asm!("dsb");
+    }
+    // リセットされるまで待つ
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+// wfi・wfeで通常のスリープではなくディープスリープに入るようにする
+pub fn set_sleepdeep(deep: bool) {
+    unsafe {
+        let scr = read_volatile(SCR_ADDR as *const u32);
+        let scr = if deep { scr | SCR_SLEEPDEEP } else { scr & !SCR_SLEEPDEEP };
+        write_volatile(SCR_ADDR as *mut u32, scr);
+    }
+}
+
+// FPUを有効にし、例外発生時のFPUレジスタの自動・遅延スタッキングを設定する
+pub fn enable_fpu() {
+    unsafe {
+        let cpacr = read_volatile(CPACR_ADDR as *const u32);
+        write_volatile(CPACR_ADDR as *mut u32, cpacr | CPACR_CP10_CP11);
+        let fpccr = read_volatile(FPCCR_ADDR as *const u32);
+        write_volatile(FPCCR_ADDR as *mut u32, fpccr | FPCCR_ASPEN | FPCCR_LSPEN);
+        #[cfg(not(test))]
+        asm!("dsb", "isb");
+    }
+}
@@ -1,35 +1,514 @@
-use crate::process::Process;
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::slice;
+use core::str;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+use cortex_m_semihosting::hprintln;
+use crate::process::{EntryPoint, HeapStack, Pid, Process, ProcessState, RestartPolicy, SwitchReason, EXIT_CODE_FAULT, NUM_PRIORITIES};
+use crate::fault::FaultStatus;
 use crate::linked_list::{LinkedList, ListItem};
+use crate::process_table::{ProcessInfo, ProcessTable};
+use crate::scb;
+use crate::syscall::{self, Error};
+use crate::systick;
+use crate::timer::TimerTable;
+
+// sched-priorityは既定で有効なので、sched-round-robinかsched-edfを指定した場合はそちらを使う
+#[cfg(all(feature = "sched-round-robin", not(feature = "sched-edf")))]
+pub mod round_robin;
+#[cfg(all(feature = "sched-priority", not(any(feature = "sched-round-robin", feature = "sched-edf"))))]
+pub mod priority;
+#[cfg(feature = "sched-edf")]
+pub mod edf;
+
+#[cfg(all(feature = "sched-round-robin", feature = "sched-edf"))]
+compile_error!("only one of the features sched-round-robin and sched-edf can be enabled");
+
+#[cfg(not(any(feature = "sched-round-robin", feature = "sched-priority", feature = "sched-edf")))]
+compile_error!("one of the features sched-round-robin, sched-priority or sched-edf must be enabled");
+
+#[cfg(all(feature = "sched-round-robin", not(feature = "sched-edf")))]
+type Policy<'a> = round_robin::RoundRobin<'a>;
+#[cfg(all(feature = "sched-priority", not(any(feature = "sched-round-robin", feature = "sched-edf"))))]
+type Policy<'a> = priority::FixedPriority<'a>;
+#[cfg(feature = "sched-edf")]
+type Policy<'a> = edf::EarliestDeadlineFirst<'a>;
+
+// スケジューリングポリシー
+// 実行可能なアプリはポリシー側のキューで管理する
+pub trait SchedulingPolicy<'a> {
+    // 次に実行するアプリをキューから取り出す
+    fn next(&mut self) -> Option<&'a mut ListItem<'a, Process<'a>>>;
+
+    // tickごとに呼ばれる。trueを返すと実行中のアプリをプリエンプトする
+    fn on_tick(&mut self, current: &Process<'a>) -> bool;
+
+    // システムコールの処理後に呼ばれる。trueを返すと実行中のアプリをプリエンプトする
+    // システムコールで起床したアプリが実行中のアプリより優先される場合に使う
+    fn should_preempt(&self, _current: &Process<'a>) -> bool {
+        false
+    }
+
+    // 新規作成・起床によってアプリが実行可能になった
+    fn on_wake(&mut self, item: &'a mut ListItem<'a, Process<'a>>);
+
+    // 実行中のアプリがyieldまたはプリエンプトされた
+    fn on_yield(&mut self, item: &'a mut ListItem<'a, Process<'a>>);
+
+    // 実行中のアプリがスリープ・ブロック・終了した
+    fn on_block(&mut self, _process: &Process<'a>) {}
+}
+
+// 実行可能なアプリがないときに、次に起床するアプリまでのtick数(いなければNone)を受け取る
+// trueを返すとidleアプリがディープスリープに入る
+// ボード側で起床要因を設定しておく必要がある
+pub type SleepHook = fn(idle_ticks: Option<u64>) -> bool;
 
 pub struct Scheduler<'a> {
-    list: LinkedList<'a, Process<'a>>,
+    policy: Policy<'a>,
+    // 起床時刻の早い順に並べる
+    sleeping: LinkedList<'a, Process<'a>>,
+    table: ProcessTable<'a>,
+    // どのキューにもつながず、実行可能なアプリがないときだけ実行する
+    idle: Process<'a>,
+    sleep_hook: Option<SleepHook>,
+    timers: TimerTable<'a>,
+    // タイマーのコールバックを呼ぶアプリ
+    timer_task: Option<Pid>,
+    // 満了したタイマーがなくてブロックしているタイマータスク
+    timer_waiter: Option<&'a mut ListItem<'a, Process<'a>>>,
+}
+
+// 実行中のアプリ。カーネルのフォールトやパニックを報告するときに使う
+// 書き込むのはカーネルだけで、フォールトハンドラなどから読むのでアトミック変数に置く
+static CURRENT_PID: AtomicU32 = AtomicU32::new(0);
+static CURRENT_NAME: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+static CURRENT_NAME_LEN: AtomicUsize = AtomicUsize::new(0);
+static CURRENT_STACK_BASE: AtomicUsize = AtomicUsize::new(0);
+static CURRENT_STACK_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+fn set_current(process: Option<&Process>) {
+    let (pid, name) = process.map_or((0, ""), |p| (p.pid(), p.name()));
+    let (base, size) = process.map_or((0, 0), |p| (p.stack_base(), p.stack_size()));
+    CURRENT_NAME.store(name.as_ptr() as *mut u8, Ordering::Relaxed);
+    CURRENT_NAME_LEN.store(name.len(), Ordering::Relaxed);
+    CURRENT_STACK_BASE.store(base, Ordering::Relaxed);
+    CURRENT_STACK_SIZE.store(size, Ordering::Relaxed);
+    CURRENT_PID.store(pid, Ordering::Release);
 }
 
+// カーネルが実行中(またはシステムコールを処理中)のアプリのPIDと名前
+pub fn current_process() -> Option<(Pid, &'static str)> {
+    let pid = CURRENT_PID.load(Ordering::Acquire);
+    if pid == 0 {
+        return None;
+    }
+    let ptr = CURRENT_NAME.load(Ordering::Relaxed);
+    let len = CURRENT_NAME_LEN.load(Ordering::Relaxed);
+    let name = unsafe { str::from_utf8_unchecked(slice::from_raw_parts(ptr, len)) };
+    Some((pid, name))
+}
+
+// カーネルが実行中(またはシステムコールを処理中)のアプリのスタックの範囲
+pub fn current_stack() -> Option<(usize, usize)> {
+    if CURRENT_PID.load(Ordering::Acquire) == 0 {
+        return None;
+    }
+    let base = CURRENT_STACK_BASE.load(Ordering::Relaxed);
+    let size = CURRENT_STACK_SIZE.load(Ordering::Relaxed);
+    Some((base, base + size))
+}
+
+// スタックは最低でも例外フレームと少しの作業領域が必要
+pub const MIN_STACK_SIZE: usize = 256;
+// タイマーのコールバックはタイマータスクのスタックで実行される
+pub const TIMER_TASK_STACK_SIZE: usize = 1024;
+
 impl<'a> Scheduler<'a> {
     pub fn new() -> Self {
+        // MPUのリージョンにするため、サイズと同じ境界に揃える
+        #[repr(C, align(256))]
+        struct IdleStack([u8; MIN_STACK_SIZE]);
+        #[link_section = ".app_stack"]
+        static mut IDLE_STACK: IdleStack = IdleStack([0; MIN_STACK_SIZE]);
+        static IDLE_STACK_LEN: usize = MIN_STACK_SIZE;
+
+        let idle = Process::new(
+            "idle",
+            &raw mut IDLE_STACK as *mut u8,
+            &IDLE_STACK_LEN,
+            idle_main,
+            0,
+            (NUM_PRIORITIES - 1) as u8,
+        );
         Scheduler {
-            list: LinkedList::new(),
+            policy: Policy::new(),
+            sleeping: LinkedList::new(),
+            table: ProcessTable::new(),
+            idle,
+            sleep_hook: None,
+            timers: TimerTable::new(),
+            timer_task: None,
+            timer_waiter: None,
+        }
+    }
+
+    // タイマーのコールバックを呼ぶタスクを最高優先度で起動する
+    // コールバックでフォールトしても再起動する
+    pub fn start_timer_service(&mut self) -> Result<Pid, Error> {
+        let pid = self.spawn(timer_task_main, 0, TIMER_TASK_STACK_SIZE, "timer", 0)?;
+        self.process_mut(pid).unwrap().set_restart_policy(RestartPolicy::Always);
+        self.timer_task = Some(pid);
+        Ok(pid)
+    }
+
+    pub fn is_timer_task(&self, pid: Pid) -> bool {
+        self.timer_task == Some(pid)
+    }
+
+    pub fn timers(&mut self) -> &mut TimerTable<'a> {
+        &mut self.timers
+    }
+
+    pub fn set_sleep_hook(&mut self, hook: SleepHook) {
+        self.sleep_hook = Some(hook);
+    }
+
+    // アプリを登録してPIDを割り当てる
+    pub fn push(&mut self, item: &'a mut ListItem<'a, Process<'a>>) -> Result<Pid, Error> {
+        let pid = self.table.alloc(item).ok_or(Error::NoMem)?;
+        self.wake(item);
+        Ok(pid)
+    }
+
+    // スタックとListItemをヒープに確保してアプリを起動する
+    // スタックのサイズは2の累乗に切り上げる。アプリが終了すると解放される
+    pub fn spawn(
+        &mut self,
+        entry: EntryPoint,
+        arg: usize,
+        stack_size: usize,
+        name: &'static str,
+        priority: u8,
+    ) -> Result<Pid, Error> {
+        if stack_size < MIN_STACK_SIZE || (priority as usize) >= NUM_PRIORITIES {
+            return Err(Error::Inval);
+        }
+
+        let stack_size = stack_size.checked_next_power_of_two().ok_or(Error::Inval)?;
+        let stack = HeapStack::new(stack_size).ok_or(Error::NoMem)?;
+        let process = Process::with_heap_stack(name, stack, entry, arg, priority);
+        let item = Box::into_raw(Box::new(ListItem::new(process)));
+        self.push(unsafe { &mut *item }).inspect_err(|_| {
+            // プロセステーブルが一杯
+            drop(unsafe { Box::from_raw(item) });
+        })
+    }
+
+    pub fn process_mut(&mut self, pid: Pid) -> Option<&mut Process<'a>> {
+        self.table.get_mut(pid)
+    }
+
+    // 終了済みのアプリの終了コードを回収する
+    pub fn reap(&mut self, pid: Pid) -> Option<i32> {
+        self.table.reap(pid)
+    }
+
+    pub fn processes(&self) -> impl Iterator<Item = ProcessInfo> + '_ {
+        self.table.iter()
+    }
+
+    pub fn print_processes(&self) {
+        hprintln!("[Kernel]: PID NAME     STATE    PRIO STACK PEAK  SIZE");
+        for info in self.processes() {
+            hprintln!(
+                "[Kernel]: {:<3} {:<8} {:<8} {:<4} {:<5} {:<5} {}",
+                info.pid,
+                info.name,
+                info.state,
+                info.priority,
+                info.stack_usage,
+                info.stack_peak,
+                info.stack_size,
+            );
         }
     }
 
-    pub fn push(&mut self, item: &'a mut ListItem<'a, Process<'a>>) {
-        self.list.push(item);
+    fn wake(&mut self, item: &'a mut ListItem<'a, Process<'a>>) {
+        item.set_state(ProcessState::Ready);
+        self.policy.on_wake(item);
     }
 
-    fn schedule_next(&mut self) {
-        let current = self.list.pop().unwrap();
-        self.list.push(current);
+    // 実行を終えたアプリを状態に応じたリストに戻す
+    fn requeue(&mut self, item: &'a mut ListItem<'a, Process<'a>>) {
+        match item.state() {
+            ProcessState::Ready => self.policy.on_yield(item),
+            ProcessState::Sleeping => {
+                self.policy.on_block(item);
+                let wake_at = item.wake_at();
+                self.sleeping.insert_before(item, |p| p.wake_at() > wake_at);
+            }
+            ProcessState::Blocked => {
+                self.policy.on_block(item);
+                match item.waiting_for() {
+                    // 終了を待つアプリのwaitキューにつなぐ
+                    Some(pid) => self.table.get_mut(pid).unwrap().add_waiter(item),
+                    // タイマーが満了するまでタイマータスクを止めておく
+                    None if self.is_timer_task(item.pid()) && self.timer_waiter.is_none() => {
+                        self.timer_waiter = Some(item);
+                    }
+                    None => panic!("process {} blocked with nothing to wait for", item.name()),
+                }
+            }
+            // 終了したアプリはどのリストにも戻さない
+            ProcessState::Exited => {
+                self.policy.on_block(item);
+                hprintln!(
+                    "[Kernel]: process {} ({}) exited with code {} (stack peak {}/{})",
+                    item.pid(),
+                    item.name(),
+                    item.exit_code(),
+                    item.stack_peak(),
+                    item.stack_size(),
+                );
+
+                self.timers.delete_owned_by(item.pid());
+
+                // タイマータスクの権限が再利用されたPIDに引き継がれないようにする
+                let timer_task_exited = self.is_timer_task(item.pid());
+                if timer_task_exited {
+                    self.timer_task = None;
+                    self.timer_waiter = None;
+                }
+
+                // 待っているアプリがいれば終了コードを渡し、いなければwaitされるまで残す
+                let code = item.exit_code();
+                let waited = item.has_waiters();
+                while let Some(waiter) = item.pop_waiter() {
+                    waiter.complete_wait(code);
+                    self.wake(waiter);
+                }
+                if waited || timer_task_exited {
+                    self.table.free(item.pid());
+                } else {
+                    self.table.set_exited(item.pid(), code);
+                }
+
+                if item.is_heap_allocated() {
+                    drop(unsafe { Box::from_raw(item as *mut ListItem<'a, Process<'a>>) });
+                }
+
+                // コールバックのpanicでタイマータスクが終了したら、新しいPIDで起動し直す
+                // 満了済みのタイマーは新しいタイマータスクが引き継ぐ
+                if timer_task_exited {
+                    if let Err(e) = self.start_timer_service() {
+                        hprintln!("[Kernel]: failed to restart the timer service: {:?}", e);
+                    }
+                }
+            }
+            ProcessState::Running => unreachable!(),
+        }
+    }
+
+    // 起床時刻を過ぎたアプリを実行可能にする
+    // 時刻がnowになったので、起床時刻を過ぎたアプリを起こし、タイマーを満了させる
+    fn expire_timeouts(&mut self, now: u64) {
+        while self.sleeping.head().is_some_and(|p| p.wake_at() <= now) {
+            let item = self.sleeping.pop().unwrap();
+            self.wake(item);
+        }
+        if self.timers.expire(now) {
+            if let Some(item) = self.timer_waiter.take() {
+                self.wake(item);
+            }
+        }
+    }
+
+    // 実行待ちやブロック中のアプリも含めて、周期タスクのデッドラインを確認する
+    // 実行中のアプリ(skip)はaccount_tickで確認する
+    fn check_deadlines(&mut self, skip: Pid, now: u64) {
+        self.table.for_each_mut(|process| {
+            if process.pid() != skip {
+                report_deadline_miss(process, now);
+            }
+        });
+    }
+
+    // 再起動ポリシーに従って、フォールトしたアプリを再起動するか終了させる
+    fn handle_fault(&mut self, current: &mut Process<'a>) {
+        // spがスタックの外を指していれば、例外フレームは読まない
+        if current.context_frame_valid() {
+            let pc = current.context_frame().return_addr;
+            hprintln!(
+                "[Kernel]: process {} ({}) faulted at {:#x}",
+                current.pid(),
+                current.name(),
+                pc,
+            );
+        } else {
+            hprintln!(
+                "[Kernel]: process {} ({}) faulted with invalid sp {:#x}",
+                current.pid(),
+                current.name(),
+                current.sp(),
+            );
+        }
+        FaultStatus::take().report();
+
+        let Some(delay) = current.restart_delay() else {
+            current.exit(EXIT_CODE_FAULT);
+            return;
+        };
+        current.restart();
+        let now = systick::ticks();
+        // 周期タスクは再起動した時点から周期をやり直す
+        current.release(now + delay as u64);
+        hprintln!(
+            "[Kernel]: restarting process {} ({}) in {} ms (restart {})",
+            current.pid(),
+            current.name(),
+            systick::ticks_to_ms(delay as u64),
+            current.restarts(),
+        );
+        if delay > 0 {
+            current.sleep_until(now + delay as u64);
+        }
+    }
+
+    // 次のtickまでidleアプリを実行する
+    fn run_idle(&mut self) {
+        let now = systick::ticks();
+        let next_wake = self.sleeping.head().map(|p| p.wake_at());
+        let idle_ticks = next_wake
+            .into_iter()
+            .chain(self.timers.next_expiry())
+            .min()
+            .map(|at| at.saturating_sub(now));
+        let deep = self.sleep_hook.is_some_and(|hook| hook(idle_ticks));
+        scb::set_sleepdeep(deep);
+        #[cfg(feature = "tickless")]
+        systick::suspend(idle_ticks.unwrap_or(u64::MAX));
+        // idleアプリはシステムコールを呼ばないので、プリエンプトされたときだけ戻ってくる
+        self.idle.load_mpu();
+        self.idle.exec();
+        check_stack(&self.idle);
+        #[cfg(feature = "tickless")]
+        systick::resume();
+        scb::set_sleepdeep(false);
     }
 
     pub fn exec(&mut self) -> ! {
         loop {
-            let current = self.list.head_mut();
-            if let Some(p) = current {
-                p.exec();
-            } else {
-                unimplemented!();
+            let now = systick::ticks();
+            self.expire_timeouts(now);
+            self.check_deadlines(0, now);
+
+            let current = match self.policy.next() {
+                Some(item) => item,
+                None => {
+                    self.run_idle();
+                    continue;
+                }
+            };
+
+            // yield・終了するか、ポリシーがプリエンプトを選ぶまで同じアプリを実行する
+            current.set_state(ProcessState::Running);
+            current.load_mpu();
+            set_current(Some(current));
+            while current.state() == ProcessState::Running {
+                // ガード領域に踏み込んだスタックはMemManageフォールトとしてhandle_faultで扱う
+                // フォールトせずに番兵が壊れていたときだけ、check_stackで停止する
+                let reason = match current.exec() {
+                    _ if current.sp_in_guard() => SwitchReason::Fault,
+                    reason => reason,
+                };
+                if reason != SwitchReason::Fault {
+                    check_stack(current);
+                }
+                match reason {
+                    SwitchReason::Syscall => {
+                        syscall::dispatch(self, current);
+                        if current.state() == ProcessState::Running
+                            && self.policy.should_preempt(current)
+                        {
+                            current.set_state(ProcessState::Ready);
+                        }
+                    }
+                    SwitchReason::Preempted => {
+                        let now = systick::ticks();
+                        account_tick(current, now);
+                        self.check_deadlines(current.pid(), now);
+                        self.expire_timeouts(now);
+                        if self.policy.on_tick(current) {
+                            current.set_state(ProcessState::Ready);
+                        }
+                    }
+                    SwitchReason::Fault => self.handle_fault(current),
+                }
             }
-            self.schedule_next();
+            self.requeue(current);
+            set_current(None);
+        }
+    }
+}
+
+// アプリから戻るたびに、スタックの底の番兵が壊れていないか確認する
+// あふれたスタックは隣のアプリのスタックを壊しているので、続行せずに停止する
+fn check_stack(process: &Process) {
+    if process.stack_overflowed() {
+        hprintln!(
+            "[Kernel]: stack overflow in process {} ({}): sp {:#x}, stack {:#x}-{:#x}",
+            process.pid(),
+            process.name(),
+            process.sp(),
+            process.stack_base(),
+            process.stack_base() + process.stack_size(),
+        );
+        panic!("stack overflow in process {}", process.name());
+    }
+}
+
+// 実行中の周期タスクの実行時間とデッドラインを確認する
+fn account_tick(current: &mut Process, now: u64) {
+    if current.consume_budget() {
+        hprintln!(
+            "[Kernel]: process {} ({}) exhausted its WCET budget (release {})",
+            current.pid(),
+            current.name(),
+            current.release_at(),
+        );
+    }
+    report_deadline_miss(current, now);
+}
+
+pub fn report_deadline_miss(process: &mut Process, now: u64) {
+    if process.check_deadline(now) {
+        hprintln!(
+            "[Kernel]: process {} ({}) missed its deadline (deadline {}, now {})",
+            process.pid(),
+            process.name(),
+            process.deadline(),
+            now,
+        );
+    }
+}
+
+// 満了したタイマーのコールバックを順番に呼ぶ
+extern "C" fn timer_task_main(_arg: usize) -> i32 {
+    loop {
+        let (callback, arg) = syscall::next_timer();
+        callback(arg);
+    }
+}
+
+// 実行可能なアプリがないときは割り込みが来るまで待つ
+extern "C" fn idle_main(_arg: usize) -> i32 {
+    loop {
+        unsafe {
+            #[cfg(all(not(test), not(feature = "idle-wfe")))]
+            asm!("wfi");
+            #[cfg(all(not(test), feature = "idle-wfe"))]
+            asm!("wfe");
         }
     }
-}
\ No newline at end of file
+}
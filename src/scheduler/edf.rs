@@ -0,0 +1,46 @@
+use super::SchedulingPolicy;
+use crate::process::Process;
+use crate::linked_list::{LinkedList, ListItem};
+
+// 絶対デッドラインの早い順に実行する
+// デッドラインが同じアプリ同士はラウンドロビンになる
+pub struct EarliestDeadlineFirst<'a> {
+    // デッドラインの早い順に並べる
+    ready: LinkedList<'a, Process<'a>>,
+}
+
+impl<'a> EarliestDeadlineFirst<'a> {
+    pub fn new() -> Self {
+        EarliestDeadlineFirst {
+            ready: LinkedList::new(),
+        }
+    }
+
+    fn insert(&mut self, item: &'a mut ListItem<'a, Process<'a>>) {
+        let deadline = item.deadline();
+        self.ready.insert_before(item, |p| p.deadline() > deadline);
+    }
+}
+
+impl<'a> SchedulingPolicy<'a> for EarliestDeadlineFirst<'a> {
+    fn next(&mut self) -> Option<&'a mut ListItem<'a, Process<'a>>> {
+        self.ready.pop()
+    }
+
+    fn on_tick(&mut self, current: &Process<'a>) -> bool {
+        self.ready.head().is_some_and(|p| p.deadline() <= current.deadline())
+    }
+
+    // より早いデッドラインのアプリが起床していれば切り替える
+    fn should_preempt(&self, current: &Process<'a>) -> bool {
+        self.ready.head().is_some_and(|p| p.deadline() < current.deadline())
+    }
+
+    fn on_wake(&mut self, item: &'a mut ListItem<'a, Process<'a>>) {
+        self.insert(item);
+    }
+
+    fn on_yield(&mut self, item: &'a mut ListItem<'a, Process<'a>>) {
+        self.insert(item);
+    }
+}
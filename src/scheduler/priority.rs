@@ -0,0 +1,47 @@
+use super::SchedulingPolicy;
+use crate::process::{Process, NUM_PRIORITIES};
+use crate::linked_list::{LinkedList, ListItem};
+
+// 固定優先度スケジューリング
+// 同じ優先度の中ではラウンドロビンになる
+pub struct FixedPriority<'a> {
+    // 優先度ごとの実行可能キュー
+    ready: [LinkedList<'a, Process<'a>>; NUM_PRIORITIES],
+}
+
+impl<'a> FixedPriority<'a> {
+    pub fn new() -> Self {
+        FixedPriority {
+            ready: core::array::from_fn(|_| LinkedList::new()),
+        }
+    }
+}
+
+impl<'a> SchedulingPolicy<'a> for FixedPriority<'a> {
+    // 最も優先度の高いキューの先頭を取り出す
+    fn next(&mut self) -> Option<&'a mut ListItem<'a, Process<'a>>> {
+        self.ready.iter_mut().find_map(|list| list.pop())
+    }
+
+    // 同じか高い優先度のアプリが待っていれば切り替える
+    fn on_tick(&mut self, current: &Process<'a>) -> bool {
+        self.ready[..=current.priority() as usize]
+            .iter()
+            .any(|list| !list.is_empty())
+    }
+
+    // より高い優先度のアプリが起床していれば切り替える
+    fn should_preempt(&self, current: &Process<'a>) -> bool {
+        self.ready[..current.priority() as usize]
+            .iter()
+            .any(|list| !list.is_empty())
+    }
+
+    fn on_wake(&mut self, item: &'a mut ListItem<'a, Process<'a>>) {
+        self.ready[item.priority() as usize].push(item);
+    }
+
+    fn on_yield(&mut self, item: &'a mut ListItem<'a, Process<'a>>) {
+        self.ready[item.priority() as usize].push(item);
+    }
+}
@@ -0,0 +1,34 @@
+use super::SchedulingPolicy;
+use crate::process::Process;
+use crate::linked_list::{LinkedList, ListItem};
+
+// 優先度を無視して、到着順に1tickずつ実行する
+pub struct RoundRobin<'a> {
+    ready: LinkedList<'a, Process<'a>>,
+}
+
+impl<'a> RoundRobin<'a> {
+    pub fn new() -> Self {
+        RoundRobin {
+            ready: LinkedList::new(),
+        }
+    }
+}
+
+impl<'a> SchedulingPolicy<'a> for RoundRobin<'a> {
+    fn next(&mut self) -> Option<&'a mut ListItem<'a, Process<'a>>> {
+        self.ready.pop()
+    }
+
+    fn on_tick(&mut self, _current: &Process<'a>) -> bool {
+        !self.ready.is_empty()
+    }
+
+    fn on_wake(&mut self, item: &'a mut ListItem<'a, Process<'a>>) {
+        self.ready.push(item);
+    }
+
+    fn on_yield(&mut self, item: &'a mut ListItem<'a, Process<'a>>) {
+        self.ready.push(item);
+    }
+}
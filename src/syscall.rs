@@ -0,0 +1,489 @@
+use core::arch::asm;
+use core::fmt;
+use core::mem::{align_of, size_of, MaybeUninit};
+use core::slice;
+use core::str;
+use cortex_m_semihosting::{hprint, hprintln};
+use crate::mpu::{self, Access};
+use crate::panic::{self, PanicAction};
+use crate::process::{EntryPoint, Pid, Process, ProcessState, EXIT_CODE_PANIC};
+use crate::scheduler::{self, Scheduler};
+use crate::systick;
+use crate::timer::{TimerCallback, TimerId};
+
+// システムコールのABI
+// - システムコール番号はsvc命令の即値
+// - 引数はr0-r3、戻り値はr0に格納する
+// - 戻り値が負の場合はエラー
+pub const YIELD: u8 = 0;
+pub const PRINT: u8 = 1;
+pub const EXIT: u8 = 2;
+pub const SLEEP: u8 = 3;
+pub const NEXT_PERIOD: u8 = 4;
+pub const SPAWN: u8 = 5;
+pub const WAIT: u8 = 6;
+pub const TRY_WAIT: u8 = 7;
+pub const NOW: u8 = 8;
+pub const TIMER_CREATE: u8 = 9;
+pub const TIMER_START: u8 = 10;
+pub const TIMER_STOP: u8 = 11;
+pub const TIMER_RESET: u8 = 12;
+pub const TIMER_CHANGE_PERIOD: u8 = 13;
+pub const TIMER_NEXT: u8 = 14;
+pub const STACK_PEAK: u8 = 15;
+pub const PANIC: u8 = 16;
+pub const TIMER_DELETE: u8 = 17;
+
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    NoSys = -1,
+    Inval = -2,
+    NoMem = -3,
+    NoProcess = -4,
+    WouldBlock = -5,
+}
+
+impl Error {
+    fn from_raw(value: u32) -> Option<Self> {
+        match value as i32 {
+            -1 => Some(Error::NoSys),
+            -2 => Some(Error::Inval),
+            -3 => Some(Error::NoMem),
+            -4 => Some(Error::NoProcess),
+            -5 => Some(Error::WouldBlock),
+            _ => None,
+        }
+    }
+}
+
+type Handler = for<'a> fn(&mut Scheduler<'a>, &mut Process<'a>, [u32; 4]) -> Result<u32, Error>;
+
+static HANDLERS: [Handler; 18] = [
+    sys_yield,
+    sys_print,
+    sys_exit,
+    sys_sleep,
+    sys_next_period,
+    sys_spawn,
+    sys_wait,
+    sys_try_wait,
+    sys_now,
+    sys_timer_create,
+    sys_timer_start,
+    sys_timer_stop,
+    sys_timer_start,
+    sys_timer_change_period,
+    sys_timer_next,
+    sys_stack_peak,
+    sys_panic,
+    sys_timer_delete,
+];
+
+// spawnシステムコールの引数
+// カーネルはアプリが書いた値をそのまま信用できないので、ポインタは整数で受け取って確かめる
+#[repr(C)]
+pub struct SpawnArgs {
+    entry: usize,
+    arg: usize,
+    stack_size: usize,
+    priority: u8,
+    name_ptr: usize,
+    name_len: usize,
+}
+
+// アプリがsvcでカーネルに戻ってきたときに呼ばれる
+pub fn dispatch<'a>(sched: &mut Scheduler<'a>, process: &mut Process<'a>) {
+    let number = process.syscall_number();
+    let frame = process.context_frame();
+    let args = [frame.r0, frame.r1, frame.r2, frame.r3];
+
+    let result = match HANDLERS.get(number as usize) {
+        Some(handler) => handler(sched, process, args),
+        None => Err(Error::NoSys),
+    };
+
+    process.context_frame().r0 = match result {
+        Ok(value) => value,
+        Err(e) => e as i32 as u32,
+    };
+}
+
+fn sys_yield<'a>(_sched: &mut Scheduler<'a>, process: &mut Process<'a>, _args: [u32; 4]) -> Result<u32, Error> {
+    process.set_state(ProcessState::Ready);
+    Ok(0)
+}
+
+fn sys_print<'a>(_sched: &mut Scheduler<'a>, process: &mut Process<'a>, args: [u32; 4]) -> Result<u32, Error> {
+    process.check_access(args[0] as usize, args[1] as usize, Access::ReadOnly)?;
+    let bytes = unsafe { slice::from_raw_parts(args[0] as *const u8, args[1] as usize) };
+    let s = str::from_utf8(bytes).map_err(|_| Error::Inval)?;
+    hprint!("{}", s);
+    Ok(args[1])
+}
+
+// r0: 終了コード
+fn sys_exit<'a>(_sched: &mut Scheduler<'a>, process: &mut Process<'a>, args: [u32; 4]) -> Result<u32, Error> {
+    process.exit(args[0] as i32);
+    Ok(0)
+}
+
+// 引数で指定したtick数だけスリープする
+fn sys_sleep<'a>(_sched: &mut Scheduler<'a>, process: &mut Process<'a>, args: [u32; 4]) -> Result<u32, Error> {
+    if args[0] == 0 {
+        process.set_state(ProcessState::Ready);
+    } else {
+        process.sleep_until(systick::ticks() + args[0] as u64);
+    }
+    Ok(0)
+}
+
+// 周期タスクの現在のジョブを終えて、次のリリースまで待つ
+fn sys_next_period<'a>(_sched: &mut Scheduler<'a>, process: &mut Process<'a>, _args: [u32; 4]) -> Result<u32, Error> {
+    let task = process.periodic().ok_or(Error::Inval)?;
+    let now = systick::ticks();
+    scheduler::report_deadline_miss(process, now);
+
+    let next_release = process.release_at() + task.period as u64;
+    process.release(next_release);
+    if next_release > now {
+        process.sleep_until(next_release);
+    } else {
+        // 周期を超過しているので、すぐに次のジョブを始める
+        process.set_state(ProcessState::Ready);
+    }
+    Ok(0)
+}
+
+// r0: SpawnArgsへのポインタ
+// 名前はアプリが終了した後も参照するので、フラッシュにある文字列リテラルに限る
+fn sys_spawn<'a>(sched: &mut Scheduler<'a>, process: &mut Process<'a>, args: [u32; 4]) -> Result<u32, Error> {
+    let spawn_args = unsafe { user_ptr::<SpawnArgs>(process, args[0], Access::ReadOnly)?.read_volatile() };
+    if spawn_args.entry == 0 || !mpu::in_code(spawn_args.name_ptr, spawn_args.name_len) {
+        return Err(Error::Inval);
+    }
+    let entry: EntryPoint = unsafe { core::mem::transmute(spawn_args.entry) };
+    let name = unsafe { slice::from_raw_parts(spawn_args.name_ptr as *const u8, spawn_args.name_len) };
+    let name = str::from_utf8(name).map_err(|_| Error::Inval)?;
+    sched.spawn(entry, spawn_args.arg, spawn_args.stack_size, name, spawn_args.priority)
+}
+
+// r0: 終了を待つアプリのPID、r1: 終了コードの書き込み先
+// 対象のアプリがまだ動いていればブロックし、終了したときにr1に終了コードを書き込む
+fn sys_wait<'a>(sched: &mut Scheduler<'a>, process: &mut Process<'a>, args: [u32; 4]) -> Result<u32, Error> {
+    if try_reap(sched, process, args)? {
+        return Ok(0);
+    }
+    process.wait_for(args[0]);
+    Ok(0)
+}
+
+fn sys_try_wait<'a>(sched: &mut Scheduler<'a>, process: &mut Process<'a>, args: [u32; 4]) -> Result<u32, Error> {
+    if try_reap(sched, process, args)? {
+        Ok(0)
+    } else {
+        Err(Error::WouldBlock)
+    }
+}
+
+// r0: 起動してからの時間(マイクロ秒)の書き込み先
+fn sys_now<'a>(_sched: &mut Scheduler<'a>, process: &mut Process<'a>, args: [u32; 4]) -> Result<u32, Error> {
+    let out = user_ptr::<u64>(process, args[0], Access::ReadWrite)?;
+    unsafe { out.write_volatile(systick::now()) };
+    Ok(0)
+}
+
+// r0: コールバック、r1: コールバックの引数、r2: 周期(tick)、r3: 0以外なら自動で再開する
+fn sys_timer_create<'a>(sched: &mut Scheduler<'a>, process: &mut Process<'a>, args: [u32; 4]) -> Result<u32, Error> {
+    if args[0] == 0 {
+        return Err(Error::Inval);
+    }
+    let callback: TimerCallback = unsafe { core::mem::transmute(args[0] as usize) };
+    sched.timers().create(process.pid(), callback, args[1] as usize, args[2], args[3] != 0)
+}
+
+// r0: タイマーID
+// 動作中のタイマーに対してはリセットになる
+fn sys_timer_start<'a>(sched: &mut Scheduler<'a>, process: &mut Process<'a>, args: [u32; 4]) -> Result<u32, Error> {
+    check_timer_owner(sched, process, args[0])?;
+    sched.timers().start(args[0], systick::ticks())?;
+    Ok(0)
+}
+
+fn sys_timer_stop<'a>(sched: &mut Scheduler<'a>, process: &mut Process<'a>, args: [u32; 4]) -> Result<u32, Error> {
+    check_timer_owner(sched, process, args[0])?;
+    sched.timers().stop(args[0])?;
+    Ok(0)
+}
+
+// r0: タイマーID、r1: 新しい周期(tick)
+fn sys_timer_change_period<'a>(sched: &mut Scheduler<'a>, process: &mut Process<'a>, args: [u32; 4]) -> Result<u32, Error> {
+    check_timer_owner(sched, process, args[0])?;
+    sched.timers().change_period(args[0], args[1], systick::ticks())?;
+    Ok(0)
+}
+
+// r0: タイマーID
+// 停止してIDを解放する。満了済みでまだ呼ばれていないコールバックも取り消す
+fn sys_timer_delete<'a>(sched: &mut Scheduler<'a>, process: &mut Process<'a>, args: [u32; 4]) -> Result<u32, Error> {
+    check_timer_owner(sched, process, args[0])?;
+    sched.timers().delete(args[0])?;
+    Ok(0)
+}
+
+// r0: FiredTimerの書き込み先
+// タイマータスク専用。満了したタイマーがあれば書き込んで1を返し、なければブロックして0を返す
+fn sys_timer_next<'a>(sched: &mut Scheduler<'a>, process: &mut Process<'a>, args: [u32; 4]) -> Result<u32, Error> {
+    if !sched.is_timer_task(process.pid()) {
+        return Err(Error::Inval);
+    }
+    let out = user_ptr::<FiredTimer>(process, args[0], Access::ReadWrite)?;
+    match sched.timers().take_fired() {
+        Some((callback, arg)) => {
+            unsafe { out.write_volatile(FiredTimer { callback, arg }) };
+            Ok(1)
+        }
+        None => {
+            process.set_state(ProcessState::Blocked);
+            Ok(0)
+        }
+    }
+}
+
+// r0: PID。0なら自分自身
+// 起動してから最も深く使ったスタックのバイト数を返す
+fn sys_stack_peak<'a>(sched: &mut Scheduler<'a>, process: &mut Process<'a>, args: [u32; 4]) -> Result<u32, Error> {
+    let pid = args[0];
+    if pid == 0 || pid == process.pid() {
+        return Ok(process.stack_peak() as u32);
+    }
+    let target = sched.process_mut(pid).ok_or(Error::NoProcess)?;
+    Ok(target.stack_peak() as u32)
+}
+
+// アプリがパニックした。設定に従ってアプリだけを終了させるか、システムを止める
+fn sys_panic<'a>(_sched: &mut Scheduler<'a>, process: &mut Process<'a>, _args: [u32; 4]) -> Result<u32, Error> {
+    hprintln!("[Kernel]: process {} ({}) panicked", process.pid(), process.name());
+    match panic::action() {
+        PanicAction::KillProcess => {
+            process.exit(EXIT_CODE_PANIC);
+            Ok(0)
+        }
+        action => panic::abort(action),
+    }
+}
+
+// タイマーを作ったアプリか、そのコールバックを呼ぶタイマータスクだけが操作できる
+fn check_timer_owner(sched: &mut Scheduler, process: &Process, id: TimerId) -> Result<(), Error> {
+    if sched.is_timer_task(process.pid()) || sched.timers().owner(id)? == process.pid() {
+        Ok(())
+    } else {
+        Err(Error::Inval)
+    }
+}
+
+// 対象のアプリが終了済みなら終了コードを書き込んでtrueを返す
+fn try_reap<'a>(sched: &mut Scheduler<'a>, process: &mut Process<'a>, args: [u32; 4]) -> Result<bool, Error> {
+    let pid = args[0];
+    if pid == process.pid() {
+        return Err(Error::Inval);
+    }
+    let out = user_ptr::<i32>(process, args[1], Access::ReadWrite)?;
+    if let Some(code) = sched.reap(pid) {
+        unsafe { out.write_volatile(code) };
+        return Ok(true);
+    }
+    if sched.process_mut(pid).is_none() {
+        return Err(Error::NoProcess);
+    }
+    Ok(false)
+}
+
+// アプリから渡されたTへのポインタが、境界が揃っていてアプリ自身がaccessできる範囲を指していれば返す
+fn user_ptr<T>(process: &Process, addr: u32, access: Access) -> Result<*mut T, Error> {
+    if !(addr as usize).is_multiple_of(align_of::<T>()) {
+        return Err(Error::Inval);
+    }
+    process.check_access(addr as usize, size_of::<T>(), access)?;
+    Ok(addr as *mut T)
+}
+
+// timer_nextシステムコールの出力
+#[repr(C)]
+struct FiredTimer {
+    callback: TimerCallback,
+    arg: usize,
+}
+
+// 以下はアプリ側から呼び出すためのラッパー
+
+#[inline(always)]
+unsafe fn syscall<const N: u8>(r0: u32, r1: u32, r2: u32, r3: u32) -> Result<u32, Error> {
+    let ret: u32;
+    // ホストでのテストではシステムコールを呼べない
+    #[cfg(test)]
+    unreachable!("svc {} on the host", N);
+    #[cfg(not(test))]
+    asm!(
+        "svc {number}",
+        number = const N,
+        inlateout("r0") r0 => ret,
+        in("r1") r1,
+        in("r2") r2,
+        in("r3") r3,
+    );
+    match Error::from_raw(ret) {
+        Some(e) => Err(e),
+        None => Ok(ret),
+    }
+}
+
+pub fn yield_now() {
+    let _ = unsafe { syscall::<YIELD>(0, 0, 0, 0) };
+}
+
+pub fn exit(code: i32) -> ! {
+    let _ = unsafe { syscall::<EXIT>(code as u32, 0, 0, 0) };
+    unreachable!();
+}
+
+pub fn sleep(ticks: u32) {
+    let _ = unsafe { syscall::<SLEEP>(ticks, 0, 0, 0) };
+}
+
+pub fn sleep_ms(ms: u32) {
+    sleep(systick::ms_to_ticks(ms));
+}
+
+pub fn wait_next_period() -> Result<u32, Error> {
+    unsafe { syscall::<NEXT_PERIOD>(0, 0, 0, 0) }
+}
+
+pub fn spawn(
+    entry: EntryPoint,
+    arg: usize,
+    stack_size: usize,
+    name: &'static str,
+    priority: u8,
+) -> Result<Pid, Error> {
+    let args = SpawnArgs {
+        entry: entry as usize,
+        arg,
+        stack_size,
+        priority,
+        name_ptr: name.as_ptr() as usize,
+        name_len: name.len(),
+    };
+    unsafe { syscall::<SPAWN>(&args as *const SpawnArgs as u32, 0, 0, 0) }
+}
+
+// アプリの終了を待って終了コードを返す
+pub fn wait(pid: Pid) -> Result<i32, Error> {
+    let mut code = 0i32;
+    unsafe { syscall::<WAIT>(pid, &mut code as *mut i32 as u32, 0, 0) }?;
+    Ok(code)
+}
+
+// アプリが終了していなければErr(Error::WouldBlock)を返す
+pub fn try_wait(pid: Pid) -> Result<i32, Error> {
+    let mut code = 0i32;
+    unsafe { syscall::<TRY_WAIT>(pid, &mut code as *mut i32 as u32, 0, 0) }?;
+    Ok(code)
+}
+
+// 起動してからの時間(マイクロ秒)
+pub fn now() -> u64 {
+    let mut now = 0u64;
+    let _ = unsafe { syscall::<NOW>(&mut now as *mut u64 as u32, 0, 0, 0) };
+    now
+}
+
+// 周期periodのタイマーを停止した状態で作る
+// 満了するとタイマータスクの中でcallback(arg)が呼ばれる
+pub fn timer_create(callback: TimerCallback, arg: usize, period: u32, auto_reload: bool) -> Result<TimerId, Error> {
+    let callback = callback as usize as u32;
+    unsafe { syscall::<TIMER_CREATE>(callback, arg as u32, period, auto_reload as u32) }
+}
+
+pub fn timer_start(id: TimerId) -> Result<(), Error> {
+    unsafe { syscall::<TIMER_START>(id, 0, 0, 0) }.map(|_| ())
+}
+
+pub fn timer_stop(id: TimerId) -> Result<(), Error> {
+    unsafe { syscall::<TIMER_STOP>(id, 0, 0, 0) }.map(|_| ())
+}
+
+// 現在から周期を数え直す
+pub fn timer_reset(id: TimerId) -> Result<(), Error> {
+    unsafe { syscall::<TIMER_RESET>(id, 0, 0, 0) }.map(|_| ())
+}
+
+// 周期を変えて、現在から開始する
+pub fn timer_change_period(id: TimerId, period: u32) -> Result<(), Error> {
+    unsafe { syscall::<TIMER_CHANGE_PERIOD>(id, period, 0, 0) }.map(|_| ())
+}
+
+// 停止してIDを解放する
+pub fn timer_delete(id: TimerId) -> Result<(), Error> {
+    unsafe { syscall::<TIMER_DELETE>(id, 0, 0, 0) }.map(|_| ())
+}
+
+// 満了したタイマーが見つかるまでブロックする
+pub(crate) fn next_timer() -> (TimerCallback, usize) {
+    let mut fired = MaybeUninit::<FiredTimer>::uninit();
+    loop {
+        if let Ok(1) = unsafe { syscall::<TIMER_NEXT>(fired.as_mut_ptr() as u32, 0, 0, 0) } {
+            let fired = unsafe { fired.assume_init() };
+            return (fired.callback, fired.arg);
+        }
+    }
+}
+
+// パニックハンドラから呼ぶ。戻ってこない
+pub fn panic() -> ! {
+    let _ = unsafe { syscall::<PANIC>(0, 0, 0, 0) };
+    // パニックハンドラの中なので、unreachable!()で再びパニックさせない
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+// pidのアプリ(0なら自分自身)が最も深く使ったスタックのバイト数
+pub fn stack_peak(pid: Pid) -> Result<usize, Error> {
+    unsafe { syscall::<STACK_PEAK>(pid, 0, 0, 0) }.map(|peak| peak as usize)
+}
+
+pub fn print(s: &str) -> Result<u32, Error> {
+    unsafe { syscall::<PRINT>(s.as_ptr() as u32, s.len() as u32, 0, 0) }
+}
+
+pub struct Stdout;
+
+impl fmt::Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print(s).map(|_| ()).map_err(|_| fmt::Error)
+    }
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        {
+            use core::fmt::Write;
+            let _ = write!($crate::syscall::Stdout, $($arg)*);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::print!("\n")
+    };
+    ($($arg:tt)*) => {
+        {
+            use core::fmt::Write;
+            let _ = writeln!($crate::syscall::Stdout, $($arg)*);
+        }
+    };
+}
@@ -1,17 +1,258 @@
 use cortex_m_semihosting::hprintln;
 use core::ptr::{read_volatile, write_volatile};
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 const CSR_ADDR: usize = 0xE000_E010;
 const RVR_ADDR: usize = 0xE000_E014;
 const CVR_ADDR: usize = 0xE000_E018;
-const CALIB_ADDR: usize = 0xE000_E01C;
 
-pub fn init() {
+// 64bitのアトミック変数は使えないので、上位と下位に分けて保持する
+static TICKS_HI: AtomicU32 = AtomicU32::new(0);
+static TICKS_LO: AtomicU32 = AtomicU32::new(0);
+
+// 1tick分のRVRの値
+static RELOAD: AtomicU32 = AtomicU32::new(0);
+// 1秒あたりのtick数
+// アプリ側のms_to_ticksからも読むので、共有データに置く
+#[link_section = ".shared"]
+static TICK_HZ: AtomicU32 = AtomicU32::new(1);
+// SysTickがカウントするクロックの周波数
+static CLOCK_HZ: AtomicU32 = AtomicU32::new(1);
+// now()がCOUNTFLAGを読んで消したが、まだtick数に加算されていない
+static WRAP_PENDING: AtomicBool = AtomicBool::new(false);
+// 次のSysTick割り込みまでのtick数。tickless idle中以外は1
+static TICK_STEP: AtomicU32 = AtomicU32::new(1);
+
+const CSR_COUNTFLAG: u32 = 1 << 16;
+const RVR_MAX: u32 = 0x00FF_FFFF;
+// カウンタ有効・割り込み有効
+const CSR_RUN: u32 = (1 << 1) | (1 << 0);
+const CSR_CLKSOURCE: u32 = 1 << 2;
+
+// SysTickがカウントするクロック
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockSource {
+    // プロセッサクロック
+    Processor,
+    // 外部参照クロック。STM32F401ではHCLK/8
+    External,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitError {
+    // tick_hzが0
+    ZeroTickRate,
+    // 1tick分のカウント数がRVRの24bitに収まらないか、2未満
+    ReloadOutOfRange,
+}
+
+// clock_hzのsourceでカウントし、1秒にtick_hz回割り込みを発生させる
+pub fn init(clock_hz: u32, source: ClockSource, tick_hz: u32) -> Result<(), InitError> {
     hprintln!("Systick init");
+    if tick_hz == 0 {
+        return Err(InitError::ZeroTickRate);
+    }
+    let count = clock_hz / tick_hz;
+    if !(2..=RVR_MAX + 1).contains(&count) {
+        return Err(InitError::ReloadOutOfRange);
+    }
+    let clksource = match source {
+        ClockSource::Processor => CSR_CLKSOURCE,
+        ClockSource::External => 0,
+    };
+    let reload = count - 1;
+    RELOAD.store(reload, Ordering::Relaxed);
+    TICK_HZ.store(tick_hz, Ordering::Relaxed);
+    CLOCK_HZ.store(clock_hz, Ordering::Relaxed);
     unsafe {
+        write_volatile(CSR_ADDR as *mut u32, 0);
+        write_volatile(RVR_ADDR as *mut u32, reload);
         write_volatile(CVR_ADDR as *mut u32, 0);
-        let calib_val = read_volatile(CALIB_ADDR as *const u32) & 0x00FF_FFFF;
-        write_volatile(RVR_ADDR as *mut u32, calib_val * 500);
-        write_volatile(CSR_ADDR as *mut u32, 0x3);
+        write_volatile(CSR_ADDR as *mut u32, CSR_RUN | clksource);
+    }
+    Ok(())
+}
+
+// SysTick割り込みから呼ばれる
+// CSRを読んでCOUNTFLAGを消しておかないと、now()が処理済みの0到達を未処理と見なしてしまう
+pub fn tick() {
+    unsafe {
+        read_volatile(CSR_ADDR as *const u32);
+    }
+    advance(TICK_STEP.swap(1, Ordering::Relaxed));
+}
+
+fn advance(step: u32) {
+    let (lo, carry) = TICKS_LO.load(Ordering::Relaxed).overflowing_add(step);
+    if carry {
+        TICKS_HI.fetch_add(1, Ordering::Relaxed);
+    }
+    TICKS_LO.store(lo, Ordering::Release);
+    WRAP_PENDING.store(false, Ordering::Relaxed);
+}
+
+// 次の割り込みをidle_ticks後のtickの境界まで遅らせる
+// 現在のtickで経過した分だけ最初の周期を短くするので、tickの境界はずれない
+// RVRに収まらないほど長い場合は、収まる最大のtick数で一度起きる
+#[cfg(feature = "tickless")]
+pub fn suspend(idle_ticks: u64) {
+    let reload = RELOAD.load(Ordering::Relaxed);
+    let period = reload + 1;
+    unsafe {
+        #[cfg(not(test))]
+        asm!("cpsid i");
+        let cvr = read_volatile(CVR_ADDR as *const u32);
+        let wrapped = read_volatile(CSR_ADDR as *const u32) & CSR_COUNTFLAG != 0;
+        if wrapped {
+            WRAP_PENDING.store(true, Ordering::Relaxed);
+        }
+        // 0に到達済みなら、すぐに割り込みが入るので遅らせない
+        if !WRAP_PENDING.load(Ordering::Relaxed) {
+            let elapsed = reload - cvr;
+            let step = idle_ticks.min(((RVR_MAX + 1 + elapsed) / period) as u64) as u32;
+            if step > 1 {
+                TICK_STEP.store(step, Ordering::Relaxed);
+                rearm(step * period - elapsed, reload);
+            }
+        }
+        #[cfg(not(test))]
+        asm!("cpsie i");
+    }
+}
+
+// SysTick以外の割り込みで起きた場合は、経過したtick数を足して1tickごとの割り込みに戻す
+// 現在のtickで経過した分は、次の周期を短くして持ち越す
+#[cfg(feature = "tickless")]
+pub fn resume() {
+    let reload = RELOAD.load(Ordering::Relaxed);
+    let period = reload + 1;
+    unsafe {
+        #[cfg(not(test))]
+        asm!("cpsid i");
+        let step = TICK_STEP.load(Ordering::Relaxed);
+        if step > 1 {
+            // CVRの後にCSRを読めば、その間に0になってもCOUNTFLAGで分かる
+            let cvr = read_volatile(CVR_ADDR as *const u32);
+            let wrapped = read_volatile(CSR_ADDR as *const u32) & CSR_COUNTFLAG != 0;
+            if wrapped {
+                WRAP_PENDING.store(true, Ordering::Relaxed);
+            }
+            // 0に到達済みなら、保留中の割り込みがstep分を足す。RVRは元に戻っている
+            if !WRAP_PENDING.load(Ordering::Relaxed) {
+                let since = step * period - 1 - cvr;
+                TICK_STEP.store(1, Ordering::Relaxed);
+                advance(since / period);
+                // RVRに0を書くとカウンタが止まるので、最低2クロックにする
+                rearm((period - since % period).max(2), reload);
+            }
+        }
+        #[cfg(not(test))]
+        asm!("cpsie i");
+    }
+}
+
+// 次の0到達をfirstクロック後にし、その後はreload+1クロックごとに戻す
+// CVRに書くと次のクロックでRVRを読み込むので、その後でRVRを書き換えても現在の周期は変わらない
+#[cfg(feature = "tickless")]
+unsafe fn rearm(first: u32, reload: u32) {
+    write_volatile(RVR_ADDR as *mut u32, first - 1);
+    write_volatile(CVR_ADDR as *mut u32, 0);
+    write_volatile(RVR_ADDR as *mut u32, reload);
+}
+
+// 起動してからのtick数
+pub fn ticks() -> u64 {
+    loop {
+        let hi = TICKS_HI.load(Ordering::Acquire);
+        let lo = TICKS_LO.load(Ordering::Acquire);
+        // 読んでいる途中で上位が繰り上がった場合は読み直す
+        if hi == TICKS_HI.load(Ordering::Acquire) {
+            return ((hi as u64) << 32) | lo as u64;
+        }
+    }
+}
+
+// 起動してからの時間(マイクロ秒)
+// tick数にCVRから求めた現在のtick内の経過時間を足す
+pub fn now() -> u64 {
+    let primask: u32;
+    #[cfg(not(test))]
+    unsafe {
+        asm!("mrs {}, PRIMASK", "cpsid i", out(reg) primask);
+    }
+    // ホストでのテストでは割り込みを扱わない
+    #[cfg(test)]
+    {
+        primask = 1;
+    }
+    // 割り込みを止めている間にカウンタが0になると、tick数は加算されずにCOUNTFLAGだけ立つ
+    // COUNTFLAGは読むと消えるので、加算されるまでWRAP_PENDINGで覚えておく
+    let ticks = ticks();
+    let (cvr, wrapped) = unsafe {
+        let cvr = read_volatile(CVR_ADDR as *const u32);
+        let mut wrapped = read_volatile(CSR_ADDR as *const u32) & CSR_COUNTFLAG != 0;
+        if wrapped {
+            WRAP_PENDING.store(true, Ordering::Relaxed);
+        }
+        wrapped |= WRAP_PENDING.load(Ordering::Relaxed);
+        // CVRを読んだ後に0になった場合は、読み直した値を使う
+        let cvr = if wrapped { read_volatile(CVR_ADDR as *const u32) } else { cvr };
+        (cvr, wrapped)
+    };
+    let step = TICK_STEP.load(Ordering::Relaxed);
+    if primask & 1 == 0 {
+        #[cfg(not(test))]
+        unsafe {
+            asm!("cpsie i");
+        }
+    }
+
+    // tickless idle中は、最後のtickの境界からstep tick後にCVRが0になる
+    // 0に到達した後は、RVRに戻した1tickの周期で数えている
+    let period = RELOAD.load(Ordering::Relaxed) as u64 + 1;
+    let (ticks, end) = if wrapped { (ticks + step as u64, period) } else { (ticks, step as u64 * period) };
+    let cycles = ticks * period + (end - 1 - cvr as u64);
+    (cycles as u128 * 1_000_000 / CLOCK_HZ.load(Ordering::Relaxed) as u128) as u64
+}
+
+// 切り上げて、少なくともmsミリ秒になるtick数を返す
+pub fn ms_to_ticks(ms: u32) -> u32 {
+    let tick_hz = TICK_HZ.load(Ordering::Relaxed) as u64;
+    (ms as u64 * tick_hz).div_ceil(1000).min(u32::MAX as u64) as u32
+}
+
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    let tick_hz = TICK_HZ.load(Ordering::Relaxed) as u64;
+    ticks.saturating_mul(1000) / tick_hz
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::Ordering;
+    use super::{ms_to_ticks, ticks_to_ms, TICK_HZ};
+
+    // TICK_HZを書き換えるので、1つのテストにまとめる
+    #[test]
+    fn test_tick_conversion() {
+        TICK_HZ.store(100, Ordering::Relaxed);
+        // 少なくとも指定した時間になるように切り上げる
+        assert_eq!(0, ms_to_ticks(0));
+        assert_eq!(1, ms_to_ticks(1));
+        assert_eq!(1, ms_to_ticks(10));
+        assert_eq!(2, ms_to_ticks(11));
+        assert_eq!(100, ms_to_ticks(1000));
+        assert_eq!(430, ticks_to_ms(43));
+
+        TICK_HZ.store(1000, Ordering::Relaxed);
+        assert_eq!(u32::MAX, ms_to_ticks(u32::MAX));
+
+        // 1tickが1msより長い場合は、ミリ秒に戻すと切り捨てになる
+        TICK_HZ.store(3, Ordering::Relaxed);
+        assert_eq!(1, ms_to_ticks(333));
+        assert_eq!(2, ms_to_ticks(334));
+        assert_eq!(333, ticks_to_ms(1));
+        assert_eq!(1000, ticks_to_ms(3));
+        assert_eq!(u64::MAX / 3, ticks_to_ms(u64::MAX));
     }
-}
\ No newline at end of file
+}
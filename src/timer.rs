@@ -0,0 +1,261 @@
+use alloc::boxed::Box;
+use core::ptr::NonNull;
+use crate::linked_list::{LinkedList, ListItem};
+use crate::process::Pid;
+use crate::syscall::Error;
+
+pub const MAX_TIMERS: usize = 16;
+
+pub type TimerId = u32;
+// タイマータスクの中で、引数を渡して呼ばれる
+pub type TimerCallback = extern "C" fn(usize);
+
+pub struct Timer {
+    id: TimerId,
+    // タイマーを作ったアプリ
+    owner: Pid,
+    callback: TimerCallback,
+    arg: usize,
+    // tick数
+    period: u32,
+    // trueなら満了するたびにperiod後に再開する
+    auto_reload: bool,
+    active: bool,
+    expires_at: u64,
+    // 満了したがコールバックをまだ呼んでいない回数
+    fired: u32,
+}
+
+// タイマーIDからタイマーを引くためのテーブルと、動作中のタイマーのリスト
+// タイマーIDは1からMAX_TIMERSまでで、ID-1をインデックスとして使う
+pub struct TimerTable<'a> {
+    slots: [Option<NonNull<ListItem<'a, Timer>>>; MAX_TIMERS],
+    // 満了時刻の早い順に並べる
+    active: LinkedList<'a, Timer>,
+    // コールバックを呼ぶタイマーを前回の次から探す
+    last_fired: usize,
+}
+
+impl<'a> TimerTable<'a> {
+    pub fn new() -> Self {
+        TimerTable {
+            slots: [None; MAX_TIMERS],
+            active: LinkedList::new(),
+            last_fired: 0,
+        }
+    }
+
+    // 停止した状態のタイマーを作る
+    pub fn create(
+        &mut self,
+        owner: Pid,
+        callback: TimerCallback,
+        arg: usize,
+        period: u32,
+        auto_reload: bool,
+    ) -> Result<TimerId, Error> {
+        if period == 0 {
+            return Err(Error::Inval);
+        }
+        let index = self.slots.iter().position(|slot| slot.is_none()).ok_or(Error::NoMem)?;
+        let id = index as TimerId + 1;
+        let timer = Timer {
+            id,
+            owner,
+            callback,
+            arg,
+            period,
+            auto_reload,
+            active: false,
+            expires_at: 0,
+            fired: 0,
+        };
+        let item = Box::into_raw(Box::new(ListItem::new(timer)));
+        self.slots[index] = NonNull::new(item);
+        Ok(id)
+    }
+
+    // 現在からperiod後に満了するように開始する。動作中なら満了時刻をやり直す
+    pub fn start(&mut self, id: TimerId, now: u64) -> Result<(), Error> {
+        let item = self.get(id)?;
+        if item.active {
+            self.active.remove(|t| t.id == id);
+        }
+        item.active = true;
+        item.expires_at = now + item.period as u64;
+        self.insert(item);
+        Ok(())
+    }
+
+    // 満了済みでまだ呼ばれていないコールバックも取り消す
+    pub fn stop(&mut self, id: TimerId) -> Result<(), Error> {
+        let item = self.get(id)?;
+        if item.active {
+            self.active.remove(|t| t.id == id);
+        }
+        item.active = false;
+        item.fired = 0;
+        Ok(())
+    }
+
+    // 停止してスロットを解放する
+    pub fn delete(&mut self, id: TimerId) -> Result<(), Error> {
+        self.stop(id)?;
+        let index = id as usize - 1;
+        let ptr = self.slots[index].take().unwrap();
+        drop(unsafe { Box::from_raw(ptr.as_ptr()) });
+        Ok(())
+    }
+
+    // 終了したアプリのタイマーをすべて削除する
+    pub fn delete_owned_by(&mut self, owner: Pid) {
+        for id in 1..=MAX_TIMERS as TimerId {
+            if self.owner(id) == Ok(owner) {
+                let _ = self.delete(id);
+            }
+        }
+    }
+
+    pub fn owner(&mut self, id: TimerId) -> Result<Pid, Error> {
+        Ok(self.get(id)?.owner)
+    }
+
+    // 周期を変えて、現在から開始し直す
+    pub fn change_period(&mut self, id: TimerId, period: u32, now: u64) -> Result<(), Error> {
+        if period == 0 {
+            return Err(Error::Inval);
+        }
+        self.get(id)?.period = period;
+        self.start(id, now)
+    }
+
+    // 満了したタイマーを数えてリストから外す。自動で再開するタイマーはリストに戻す
+    // 満了したタイマーがあればtrueを返す
+    pub fn expire(&mut self, now: u64) -> bool {
+        let mut fired = false;
+        while self.active.head().is_some_and(|t| t.expires_at <= now) {
+            let item = self.active.pop().unwrap();
+            item.fired += 1;
+            fired = true;
+            if item.auto_reload {
+                // 長く止まっていた場合は、遅れた分をまとめて満了させずに現在から数え直す
+                item.expires_at = (item.expires_at + item.period as u64).max(now + 1);
+                self.insert(item);
+            } else {
+                item.active = false;
+            }
+        }
+        fired
+    }
+
+    // 次にタイマーが満了する時刻
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.active.head().map(|t| t.expires_at)
+    }
+
+    // 満了したタイマーを1つ選んで、呼び出すコールバックを返す
+    pub fn take_fired(&mut self) -> Option<(TimerCallback, usize)> {
+        let start = self.last_fired;
+        let index = (0..MAX_TIMERS)
+            .map(|i| (start + i) % MAX_TIMERS)
+            .find(|&i| self.slots[i].is_some_and(|ptr| unsafe { ptr.as_ref() }.fired > 0))?;
+        self.last_fired = index + 1;
+
+        let timer = unsafe { &mut *self.slots[index].unwrap().as_ptr() };
+        timer.fired -= 1;
+        Some((timer.callback, timer.arg))
+    }
+
+    fn insert(&mut self, item: &'a mut ListItem<'a, Timer>) {
+        let expires_at = item.expires_at;
+        self.active.insert_before(item, |t| t.expires_at > expires_at);
+    }
+
+    fn get(&mut self, id: TimerId) -> Result<&'a mut ListItem<'a, Timer>, Error> {
+        let index = (id as usize).checked_sub(1).filter(|&i| i < MAX_TIMERS).ok_or(Error::Inval)?;
+        let ptr = self.slots[index].ok_or(Error::Inval)?;
+        Ok(unsafe { &mut *ptr.as_ptr() })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TimerTable, MAX_TIMERS};
+    use crate::syscall::Error;
+
+    extern "C" fn callback(_arg: usize) {}
+
+    #[test]
+    fn test_expire_order() {
+        let mut timers = TimerTable::new();
+        let id1 = timers.create(1, callback, 1, 30, false).unwrap();
+        let id2 = timers.create(1, callback, 2, 10, false).unwrap();
+        let id3 = timers.create(1, callback, 3, 20, true).unwrap();
+        timers.start(id1, 0).unwrap();
+        timers.start(id2, 0).unwrap();
+        timers.start(id3, 0).unwrap();
+
+        // 満了時刻の早い順に並ぶ
+        assert_eq!(Some(10), timers.next_expiry());
+        assert!(!timers.expire(9));
+        assert!(timers.expire(10));
+        assert_eq!(Some(2), timers.take_fired().map(|(_, arg)| arg));
+        assert!(timers.take_fired().is_none());
+        assert_eq!(Some(20), timers.next_expiry());
+
+        // 自動で再開するタイマーはperiod後に戻る
+        assert!(timers.expire(30));
+        let mut fired = [timers.take_fired().unwrap().1, timers.take_fired().unwrap().1];
+        fired.sort();
+        assert_eq!([1, 3], fired);
+        assert!(timers.take_fired().is_none());
+        assert_eq!(Some(40), timers.next_expiry());
+    }
+
+    #[test]
+    fn test_take_fired() {
+        let mut timers = TimerTable::new();
+        let id1 = timers.create(1, callback, 1, 10, true).unwrap();
+        let id2 = timers.create(1, callback, 2, 10, true).unwrap();
+        timers.start(id1, 0).unwrap();
+        timers.start(id2, 0).unwrap();
+
+        // 満了した回数だけ呼ばれ、同じタイマーばかりが選ばれないように順番に選ぶ
+        timers.expire(10);
+        timers.expire(20);
+        let fired: [usize; 4] = core::array::from_fn(|_| timers.take_fired().unwrap().1);
+        assert_eq!([1, 2, 1, 2], fired);
+        assert!(timers.take_fired().is_none());
+
+        // 停止すると、まだ呼ばれていないコールバックも取り消される
+        timers.expire(30);
+        timers.stop(id1).unwrap();
+        assert_eq!(Some(2), timers.take_fired().map(|(_, arg)| arg));
+        assert!(timers.take_fired().is_none());
+        assert_eq!(Some(40), timers.next_expiry());
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut timers = TimerTable::new();
+        for id in 1..=MAX_TIMERS as u32 {
+            assert_eq!(Ok(id), timers.create(id % 2 + 1, callback, 0, 10, false));
+        }
+        assert_eq!(Err(Error::NoMem), timers.create(1, callback, 0, 10, false));
+        assert_eq!(Err(Error::Inval), timers.create(1, callback, 0, 0, false));
+
+        // 削除したIDは再利用される
+        timers.start(3, 0).unwrap();
+        timers.delete(3).unwrap();
+        assert_eq!(None, timers.next_expiry());
+        assert_eq!(Err(Error::Inval), timers.start(3, 0));
+        assert_eq!(Ok(3), timers.create(1, callback, 0, 10, false));
+
+        // 終了したアプリのタイマーはまとめて削除される
+        timers.delete_owned_by(1);
+        assert_eq!(Err(Error::Inval), timers.owner(2));
+        assert_eq!(Ok(2), timers.owner(1));
+        assert_eq!(Err(Error::Inval), timers.delete(0));
+        assert_eq!(Err(Error::Inval), timers.delete(MAX_TIMERS as u32 + 1));
+    }
+}